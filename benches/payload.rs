@@ -0,0 +1,156 @@
+//! Run with `cargo bench --features __bench`
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flate2::write::GzEncoder;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+use tracing_core::Level;
+use tracing_newrelic::{__bench::*, Compression};
+
+// counts reallocations, i.e. how often growing buffers are moved
+struct Counting;
+
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        REALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn gzip_into(buffer: Vec<u8>, json: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(buffer, Compression::default());
+    encoder.write_all(json).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn reallocs(f: impl FnOnce()) -> usize {
+    let before = REALLOCS.load(Ordering::Relaxed);
+    f();
+    REALLOCS.load(Ordering::Relaxed) - before
+}
+
+// a log payload of `len` logs, resembling the ones sent by the exporter
+fn payload(len: usize) -> Vec<u8> {
+    let logs: Vec<_> = (0..len)
+        .map(|i| {
+            serde_json::json!({
+                "timestamp": 1_700_000_000_000u64 + i as u64,
+                "logtype": "accesslogs",
+                "level": "INFO",
+                "attributes": {
+                    "message": format!("handled request {i}"),
+                    "span.id": format!("{:016x}", i * 7919),
+                    "trace.id": format!("{:032x}", i * 104_729),
+                    "http.status_code": 200 + i % 5,
+                    "code.filepath": "src/server.rs",
+                },
+            })
+        })
+        .collect();
+
+    serde_json::to_vec(&serde_json::json!([{ "common": {}, "logs": logs }])).unwrap()
+}
+
+// the same logs as `payload`, as the exporter holds them
+fn batch(len: usize) -> Vec<NewrLogs> {
+    let logs = (0..len)
+        .map(|i| {
+            let mut log = NewrLog::new(&Level::INFO, UNIX_EPOCH);
+            log.attributes
+                .insert("message", format!("handled request {i}"));
+            log.attributes
+                .insert("span.id", format!("{:016x}", i * 7919));
+            log.attributes
+                .insert("trace.id", format!("{:032x}", i * 104_729));
+            log.attributes
+                .insert("http.status_code", (200 + i % 5) as u64);
+            log.attributes.insert("code.filepath", "src/server.rs");
+            log
+        })
+        .collect();
+
+    vec![NewrLogs {
+        logs,
+        common: NewrCommon {
+            attributes: NewrAttributes::default(),
+        },
+    }]
+}
+
+// serde_json, and simd-json with the `simd` feature
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+
+    for len in &[100, 1_000, 5_000] {
+        let batch = batch(*len);
+        group.bench_with_input(BenchmarkId::new("serde_json", len), &batch, |b, batch| {
+            b.iter(|| serde_json::to_vec(black_box(batch)).unwrap())
+        });
+        #[cfg(feature = "simd")]
+        group.bench_with_input(BenchmarkId::new("simd_json", len), &batch, |b, batch| {
+            b.iter(|| simd_json::to_vec(black_box(batch)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn gzip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("to_gz");
+
+    for len in &[100, 1_000, 5_000] {
+        let json = payload(*len);
+        group.throughput(Throughput::Bytes(json.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(json.len()), &json, |b, json| {
+            b.iter(|| to_gz(black_box(json), Compression::default()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+// the output buffer presized from the payload length, against the empty one `to_gz` uses
+fn gzip_capacity(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gz_capacity");
+
+    for len in &[1_000, 5_000] {
+        let json = payload(*len);
+
+        println!(
+            "{} bytes: {} reallocations empty, {} presized",
+            json.len(),
+            reallocs(|| drop(gzip_into(Vec::new(), &json))),
+            reallocs(|| drop(gzip_into(Vec::with_capacity(json.len() / 2), &json))),
+        );
+
+        group.throughput(Throughput::Bytes(json.len() as u64));
+        group.bench_with_input(BenchmarkId::new("empty", json.len()), &json, |b, json| {
+            b.iter(|| gzip_into(Vec::new(), black_box(json)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("presized", json.len()),
+            &json,
+            |b, json| b.iter(|| gzip_into(Vec::with_capacity(json.len() / 2), black_box(json))),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, serialize, gzip, gzip_capacity);
+criterion_main!(benches);
@@ -12,13 +12,13 @@ const HTML: &str = r#"<ul>
 </ul>"#;
 
 fn home() -> impl warp::Reply {
-    Span::current().record("name", &"GET /");
+    Span::current().record("name", "GET /");
 
     warp::reply::html(HTML)
 }
 
 async fn sleep(ms: u64) -> Result<impl warp::Reply, Infallible> {
-    Span::current().record("name", &"GET /sleep/:ms");
+    Span::current().record("name", "GET /sleep/:ms");
 
     tracing::info!(ms, "sleep {}ms", ms);
 
@@ -29,9 +29,9 @@ async fn sleep(ms: u64) -> Result<impl warp::Reply, Infallible> {
 
 fn not_found() -> impl warp::Reply {
     Span::current()
-        .record("name", &"not found")
-        .record("otel.status_code", &"ERROR")
-        .record("otel.status_description", &"not found");
+        .record("name", "not found")
+        .record("otel.status_code", "ERROR")
+        .record("otel.status_description", "not found");
 
     warp::reply::with_status(warp::reply::html(HTML), StatusCode::NOT_FOUND)
 }
@@ -40,6 +40,9 @@ fn not_found() -> impl warp::Reply {
 async fn main() {
     let newrelic = tracing_newrelic::layer(env::var("API_KEY").expect("API_KEY not found"));
 
+    // global subscriber is never dropped, flush buffered data on exit
+    let _guard = newrelic.flush_guard();
+
     let fmt = tracing_subscriber::fmt::layer();
 
     let target = tracing_subscriber::filter::Targets::new().with_target("warp", Level::INFO);
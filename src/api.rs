@@ -1,333 +1,2694 @@
-use flate2::{write::GzEncoder, Compression};
-use futures_util::join;
-use reqwest::{header::{CONTENT_ENCODING, CONTENT_TYPE}, Client, RequestBuilder};
-use serde::Serialize;
-use std::cmp::max;
-use std::time::Duration;
-use tokio::time::sleep;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures_util::{future::BoxFuture, join, stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    Client, ClientBuilder, Proxy, Request, RequestBuilder, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+use uuid::Uuid;
 
-use super::types::{NewrLogs, NewrSpans};
+use flume::{Receiver, Sender};
 
-#[derive(Clone)]
+use super::diag;
+use super::error::{ApiError, FlushError, ParseEndpointError};
+use super::handle::ApiHandle;
+use super::layer::{FlushRequest, Message};
+use super::stats::{Counters, ExporterStats, FlushStats};
+use super::types::{
+    DurationSummary, NewrAttributes, NewrEvent, NewrLogs, NewrMetric, NewrMetrics,
+    NewrMetricsCommon, NewrSpans, Value,
+};
+use super::utils::{self, default_host_name, default_service_name, parse_retry_after};
+
+const DEFAULT_USER_AGENT: &str = concat!("tracing-newrelic/", env!("CARGO_PKG_VERSION"));
+
+// client used unless one is given, identifying the crate to New Relic
+fn default_client() -> ClientBuilder {
+    Client::builder().user_agent(DEFAULT_USER_AGENT)
+}
+
+#[derive(Clone, Default)]
 /// Api Endpoint
 pub enum ApiEndpoint {
     /// United States, Default
+    #[default]
     US,
     /// European Union
     EU,
-    /// Custom
+    /// FedRAMP compliant endpoints for US government customers
+    FedRAMP,
+    /// Custom, built and validated with `TryFrom<&str>`
     Custom(String),
+    /// A local agent or collector forwarding to New Relic over plain http, see
+    /// [`ApiBuilder::with_local_forwarder`](crate::ApiBuilder::with_local_forwarder)
+    LocalForwarder(SocketAddr),
 }
 
-impl Default for ApiEndpoint {
-    fn default() -> Self {
-        ApiEndpoint::US
+impl ApiEndpoint {
+    // url of custom endpoints, without a trailing slash
+    fn base_url(&self) -> String {
+        match self {
+            ApiEndpoint::Custom(url) => url.trim_end_matches('/').to_owned(),
+            ApiEndpoint::LocalForwarder(addr) => format!("http://{addr}"),
+            _ => unreachable!("not a custom endpoint"),
+        }
+    }
+}
+
+impl FromStr for ApiEndpoint {
+    type Err = ParseEndpointError;
+
+    /// Parses `us`, `eu`, `fedramp` (case-insensitive) or a custom url starting with `http`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("us") {
+            Ok(ApiEndpoint::US)
+        } else if s.eq_ignore_ascii_case("eu") {
+            Ok(ApiEndpoint::EU)
+        } else if s.eq_ignore_ascii_case("fedramp") {
+            Ok(ApiEndpoint::FedRAMP)
+        } else if s.starts_with("http") {
+            ApiEndpoint::try_from(s)
+        } else {
+            Err(ParseEndpointError::new(
+                s,
+                "expected `us`, `eu`, `fedramp` or an url",
+            ))
+        }
+    }
+}
+
+impl TryFrom<&str> for ApiEndpoint {
+    type Error = ParseEndpointError;
+
+    /// Validates a custom url, stripping trailing slashes
+    fn try_from(url: &str) -> Result<Self, Self::Error> {
+        let host = match url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+        {
+            Some(host) => host,
+            None => {
+                return Err(ParseEndpointError::new(
+                    url,
+                    "missing `http://` or `https://` scheme",
+                ))
+            }
+        };
+
+        if host.trim_end_matches('/').is_empty() {
+            return Err(ParseEndpointError::new(url, "missing host"));
+        }
+
+        Ok(ApiEndpoint::Custom(url.trim_end_matches('/').to_owned()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// What to do with new traces when the queue is full
+pub enum OverflowPolicy {
+    /// Drops the new trace, Default
+    #[default]
+    DropNewest,
+    /// Drops the oldest queued trace to make room for the new one
+    DropOldest,
+    /// Blocks the thread closing the span until the queue has room
+    ///
+    /// Falls back to `DropNewest` with [`Api::spawn`], as blocking a runtime worker may
+    /// stall the very task draining the queue.
+    Block,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+/// Shape of the payloads sent to the Log Api
+///
+/// <https://docs.newrelic.com/docs/logs/log-api/introduction-log-api/#json-content>
+pub enum LogFormat {
+    /// Batches with a `common` block of shared attributes, Default
+    #[default]
+    Detailed,
+    /// A flat array of logs, each carrying all of its attributes
+    Simple,
+}
+
+/// Callback invoked with the error of a dropped batch
+pub type ErrorCallback = Arc<dyn Fn(&FlushError) + Send + Sync>;
+
+/// Callback invoked with the kind and the uncompressed JSON of a payload
+pub type PayloadCallback = Arc<dyn Fn(Signal, &[u8]) + Send + Sync>;
+
+/// Callback invoked with the kind and the `requestId` returned by New Relic of an accepted batch
+pub type SentCallback = Arc<dyn Fn(Signal, Option<&str>) + Send + Sync>;
+
+/// Handle replacing the key of an [`Api`] at runtime, e.g. after rotating it
+///
+/// `trace_key` and `insert_key` still take precedence when set.
+#[derive(Clone, Default)]
+pub struct KeyHandle(Arc<RwLock<Option<String>>>);
+
+impl KeyHandle {
+    /// Replaces the key for all following requests
+    pub fn set(&self, new_key: String) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Some(new_key);
     }
 }
 
+/// Sends requests to New Relic
+///
+/// Implemented for [`Client`], other implementations can e.g. record requests in tests.
+pub trait Transport: Send + Sync {
+    /// Sends a request, resolving to its response
+    fn send(&self, request: Request) -> BoxFuture<'_, Result<Response, ApiError>>;
+}
+
+impl Transport for Client {
+    fn send(&self, request: Request) -> BoxFuture<'_, Result<Response, ApiError>> {
+        Box::pin(async move { self.execute(request).await.map_err(ApiError::Network) })
+    }
+}
+
+/// Additional destination, as an endpoint and its key
+pub type Mirror = (ApiEndpoint, String);
+
+/// Decides whether a failed request is retried
+pub type RetryPredicate = Arc<dyn Fn(&ApiError) -> bool + Send + Sync>;
+
 /// New relic Api
 pub struct Api {
     /// Log Api Endpoint
     pub log_endpoint: ApiEndpoint,
     /// Trace Api Endpoint
     pub trace_endpoint: ApiEndpoint,
-    /// Api Key
+    /// Metric Api Endpoint
+    pub metric_endpoint: ApiEndpoint,
+    /// Event Api Endpoint
+    pub event_endpoint: ApiEndpoint,
+    /// Path of the Log Api on custom endpoints, defaults to `/log/v1`
+    pub log_path: Option<String>,
+    /// Path of the Trace Api on custom endpoints, defaults to `/trace/v1`
+    pub trace_path: Option<String>,
+    /// Path of the Metric Api on custom endpoints, defaults to `/metric/v1`
+    pub metric_path: Option<String>,
+    /// Account Id, required for sending custom events
+    pub account_id: Option<String>,
+    /// Insights Insert Key used by the Event Api, defaults to `key`
+    pub insert_key: Option<String>,
+    /// Api Key, can be replaced at runtime through [`Api::key_handle`]
     pub key: String,
+    /// Api Key used by the Trace Api, defaults to `key`
+    pub trace_key: Option<String>,
+    /// Destinations every batch is also sent to, e.g. an account in another region
+    ///
+    /// A flush only succeeds once all destinations accepted the data.
+    pub mirrors: Vec<Mirror>,
     /// Http Client
     pub client: Client,
-    /// Batch request size
+    /// Sends the requests built with `client`, defaults to `client` itself
+    pub transport: Option<Arc<dyn Transport>>,
+    /// Headers added to every request, replacing the standard ones with the same name
+    ///
+    /// E.g. `User-Agent`, which the default client sets to `tracing-newrelic/<version>`.
+    pub extra_headers: HeaderMap,
+    /// Timeout of a single request, timed out requests are retried
+    pub request_timeout: Duration,
+    /// Number of queued logs (and events) triggering a flush
+    pub log_batch_size: usize,
+    /// Number of queued spans (and metrics) triggering a flush
+    pub span_batch_size: usize,
+    /// Former batch size of both logs and spans, overriding them unless zero, the default
+    #[deprecated(note = "use `log_batch_size` and `span_batch_size`, or `with_batch_size`")]
     pub batch_size: usize,
+    /// Maximum number of traces waiting to be sent, zero being read as one
+    pub max_queue_len: usize,
+    /// What to do with traces once `max_queue_len` is reached
+    pub overflow_policy: OverflowPolicy,
+    /// Shape of the payloads sent to the Log Api
+    pub log_format: LogFormat,
+    /// Maximum size of the serialized payload of a single request
+    pub max_payload_bytes: usize,
+    /// Maximum time data stays buffered before being flushed
+    pub flush_interval: Duration,
+    /// Maximum number of requests in flight at once per signal
+    pub max_concurrency: usize,
+    /// Maximum number of requests per second to each destination of each signal, unlimited by default
+    ///
+    /// Requests are delayed, not dropped, once the limit is reached.
+    pub max_requests_per_second: Option<f64>,
+    /// Maximum number of requests in flight at once across all signals and mirrors
+    ///
+    /// Unlimited by default, besides `max_concurrency` per signal.
+    pub max_in_flight: Option<usize>,
+    /// Bounds of the number of items per request when adapting it to backpressure
+    ///
+    /// Starting at the maximum, it grows by one after each accepted request and halves
+    /// after each throttled, failed, too large or timed out one. Disabled by default.
+    pub adaptive_batch: Option<(usize, usize)>,
+    /// Whether to send logs, enabled by default
+    pub logs_enabled: bool,
+    /// Whether to send spans, enabled by default
+    pub traces_enabled: bool,
+    /// Whether to send metrics, enabled by default
+    pub metrics_enabled: bool,
+    /// Logs payloads instead of sending them, for debugging
+    pub dry_run: bool,
+    /// Whether to gzip payloads
+    pub compress: bool,
+    /// Gzip compression level of payloads
+    pub compression: Compression,
+    /// Base delay of the exponential backoff between retries
+    pub retry_base: Duration,
+    /// Multiplier of the delay after each retry
+    pub retry_factor: f64,
+    /// Maximum delay between two retries
+    pub retry_max: Duration,
+    /// Whether to randomize each delay by ±50%, avoiding synchronized retries across instances
+    pub jitter: bool,
+    /// Maximum number of retries before a batch is dropped
+    pub max_retries: u32,
+    /// Maximum time spent retrying before a batch is dropped, whichever of it and
+    /// `max_retries` is reached first
+    pub max_retry_elapsed: Option<Duration>,
+    /// Overrides which errors are retried, defaults to [`ApiError::is_retryable`]
+    pub retry_if: Option<RetryPredicate>,
+    /// Number of consecutive failed batches opening the circuit breaker, disabled by default
+    ///
+    /// While open, batches are dropped (or persisted to `dead_letter_dir`) without sending them.
+    pub circuit_threshold: Option<u32>,
+    /// How long the circuit breaker stays open before probing the endpoint again
+    pub circuit_cooldown: Duration,
+    /// Callback invoked whenever a batch is dropped or accepted with warnings
+    pub on_error: Option<ErrorCallback>,
+    /// Callback invoked with every uncompressed payload before it's sent
+    pub on_payload: Option<PayloadCallback>,
+    /// Callback invoked with the `requestId` of every accepted batch, e.g. for support tickets
+    pub on_sent: Option<SentCallback>,
+    /// `service.name` of logs and spans, defaults to the executable name
+    pub service_name: Option<String>,
+    /// `host.name` of logs and spans, detected when the `hostname` feature is enabled
+    pub host_name: Option<String>,
+    /// Attributes shared by every log, sent in the `common` block
+    pub common_attributes: HashMap<String, serde_json::Value>,
+    /// Attributes describing the entity, sent in the `common` block of logs and spans
+    pub resource_attributes: HashMap<String, serde_json::Value>,
+    /// Directory for persisting batches that exhausted their retries
+    pub dead_letter_dir: Option<PathBuf>,
+    /// Whether to report numeric span attributes as gauge metrics
+    pub span_metrics: bool,
+    /// Whether to merge the spans of a trace into one payload entry, so they are sent together
+    ///
+    /// Spans of a local trace are always queued together, this also merges local traces
+    /// continuing the same upstream trace, if their common attributes are the same.
+    pub coalesce_traces: bool,
+    /// Whether to report the durations of spans as `span.duration` summary metrics, by name
+    pub span_duration_metrics: bool,
+    /// Prefixes of numeric event fields reported as `count` metrics instead of attributes
+    pub counter_prefixes: Vec<String>,
 
+    stats: ExporterStats,
+    rotated_key: KeyHandle,
+    // state of each destination, keyed by url
+    targets: Mutex<HashMap<String, Arc<Target>>>,
+    in_flight: OnceLock<Semaphore>,
     logs_queue: Vec<NewrLogs>,
     spans_queue: Vec<NewrSpans>,
+    metrics_queue: Vec<NewrMetrics>,
+    events_queue: Vec<NewrEvent>,
+    // when the oldest item in queues was pushed
+    buffered_since: Option<Instant>,
+    // metrics aggregated since the last flush, summaries and counts by name
+    gauges: Vec<NewrMetric>,
+    span_durations: HashMap<String, DurationSummary>,
+    counters: HashMap<String, f64>,
+    // start of the window of aggregated metrics, its length measured on the monotonic clock
+    window_since: Option<(SystemTime, Instant)>,
 }
 
 impl Api {
-    pub(crate) async fn push(&mut self, logs: NewrLogs, traces: NewrSpans) {
-        log::debug!(
-            "pushing logs and traces, logs_queue_len={}, spans_queue_len={}",
-            self.logs_queue.len(),
-            self.spans_queue.len(),
-        );
+    /// Returns a handle to the counters of this exporter
+    pub fn stats(&self) -> ExporterStats {
+        self.stats.clone()
+    }
 
-        self.logs_queue.push(logs);
-        self.spans_queue.push(traces);
+    /// Replaces `key` for all following requests, including the ones of a running layer
+    pub fn set_key(&self, new_key: String) {
+        self.rotated_key.set(new_key);
+    }
 
-        if self.logs_queue.len() >= self.batch_size || self.spans_queue.len() >= self.batch_size {
-            self.flush().await
-        }
+    /// Returns a handle for replacing `key` once `Api` is passed to [`layer`](crate::layer())
+    pub fn key_handle(&self) -> KeyHandle {
+        self.rotated_key.clone()
     }
 
-    pub(crate) async fn flush(&mut self) {
-        if self.logs_queue.is_empty() && self.spans_queue.is_empty() {
-            return;
-        }
+    // the rotated key if any, `key` otherwise
+    fn current_key(&self) -> String {
+        self.rotated_key
+            .0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .unwrap_or_else(|| self.key.clone())
+    }
 
-        log::debug!(
-            "flushing logs and traces, logs_queue_len={}, spans_queue_len={}",
+    /// Sets both `log_batch_size` and `span_batch_size`
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.log_batch_size = batch_size;
+        self.span_batch_size = batch_size;
+        self
+    }
+
+    /// Uses a pre-configured http client, e.g. a shared connection pool
+    ///
+    /// Its `User-Agent` is left as is, use `extra_headers` to override it.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Sends requests through `transport` instead of `client`
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Rebuilds the http client to send requests through a proxy
+    ///
+    /// By default, the client uses the proxies set in `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `NO_PROXY` environment variables. Setting a proxy here disables them, and replaces
+    /// any client given to [`Api::with_client`].
+    pub fn with_proxy(mut self, proxy: Proxy) -> Result<Self, reqwest::Error> {
+        self.client = default_client().proxy(proxy).build()?;
+        Ok(self)
+    }
+
+    pub(crate) async fn push(&mut self, mut logs: NewrLogs, mut traces: NewrSpans) {
+        diag::debug!(
+            "pushing logs and traces, logs_queue_len={}, spans_queue_len={}, metrics_queue_len={}, events_queue_len={}",
             self.logs_queue.len(),
             self.spans_queue.len(),
+            self.metrics_queue.len(),
+            self.events_queue.len(),
         );
 
-        let mut logs_service = Service::new(&self.logs_queue);
-        let mut trace_service = Service::new(&self.spans_queue);
+        let service_name = self
+            .service_name
+            .get_or_insert_with(default_service_name)
+            .clone();
 
-        loop {
-            use ServiceStatus::*;
+        if self.host_name.is_none() {
+            self.host_name = default_host_name();
+        }
 
-            match join!(logs_service.send(self), trace_service.send(self)) {
-                (Timeount(d1), Timeount(d2)) => sleep(max(d1, d2)).await,
+        for common in [&mut logs.common, &mut traces.common] {
+            // attributes of the span take precedence
+            let attributes = &mut common.attributes.0;
 
-                (Timeount(d), _) | (_, Timeount(d)) => sleep(d).await,
+            attributes
+                .entry("service.name".into())
+                .or_insert_with(|| service_name.as_str().into());
 
-                (Finished, Finished) => {
-                    log::info!(
-                        "flushed logs and traces, logs_queue_len={}, spans_queue_len={}",
-                        self.logs_queue.len(),
-                        self.spans_queue.len(),
-                    );
+            if let Some(host_name) = &self.host_name {
+                attributes
+                    .entry("host.name".into())
+                    .or_insert_with(|| host_name.as_str().into());
+            }
 
-                    self.logs_queue.clear();
-                    self.spans_queue.clear();
-                    return;
-                }
+            for (key, value) in &self.resource_attributes {
+                attributes
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone().into());
+            }
+        }
+
+        for (key, value) in &self.common_attributes {
+            logs.common
+                .attributes
+                .0
+                .entry(key.clone())
+                .or_insert_with(|| value.clone().into());
+        }
 
-                _ => {}
+        if self.metrics_enabled && !self.counter_prefixes.is_empty() {
+            self.record_counters(&mut logs);
+        }
+
+        // logs with a string `eventType` are sent as custom events instead
+        if self.account_id.is_some() {
+            let (events, rest): (Vec<_>, Vec<_>) =
+                logs.logs.into_iter().partition(NewrEvent::is_event);
+
+            logs.logs = rest;
+            self.events_queue
+                .extend(events.into_iter().filter_map(NewrEvent::from_log));
+        }
+
+        if self.metrics_enabled && self.span_metrics {
+            let gauges = NewrMetric::gauges_from_spans(&traces);
+
+            if !gauges.is_empty() {
+                self.gauges.extend(gauges);
+                self.open_window();
             }
         }
-    }
-}
 
-impl Default for Api {
-    fn default() -> Self {
-        Api {
-            log_endpoint: ApiEndpoint::default(),
-            trace_endpoint: ApiEndpoint::default(),
-            key: String::new(),
-            client: Client::new(),
-            batch_size: 10,
-            logs_queue: Vec::with_capacity(10),
-            spans_queue: Vec::with_capacity(10),
+        if self.metrics_enabled && self.span_duration_metrics {
+            self.record_span_durations(&traces);
         }
-    }
-}
 
-impl From<String> for Api {
-    fn from(key: String) -> Self {
-        Api {
-            key,
-            ..Default::default()
+        if self.logs_enabled {
+            self.logs_queue.push(logs);
         }
-    }
-}
 
-impl From<&str> for Api {
-    fn from(key: &str) -> Self {
-        Api {
-            key: key.to_string(),
-            ..Default::default()
+        if self.traces_enabled {
+            self.spans_queue.push(traces);
         }
-    }
-}
 
-impl From<(String, ApiEndpoint)> for Api {
-    fn from(t: (String, ApiEndpoint)) -> Self {
-        Api {
-            key: t.0,
-            log_endpoint: t.1.clone(),
-            trace_endpoint: t.1,
-            ..Default::default()
+        self.stats
+            .counters()
+            .queue_depth
+            .store(self.spans_queue.len(), Ordering::Relaxed);
+
+        if self.buffered_since.is_none() {
+            self.buffered_since = Some(Instant::now());
+        }
+
+        let (log_batch_size, span_batch_size) = self.batch_sizes();
+
+        if self.logs_queue.len() >= log_batch_size
+            || self.spans_queue.len() >= span_batch_size
+            || self.metrics_queue.len() >= span_batch_size
+            || self.events_queue.len() >= log_batch_size
+        {
+            // errors are already logged by `flush`
+            let _ = self.flush().await;
         }
     }
-}
 
-enum ServiceStatus {
-    // Need to wait before next sending
-    Timeount(Duration),
+    /// Moves the `Api` into a background task of the current tokio runtime
+    ///
+    /// The task stops after a final flush, once all handles and their layers are dropped.
+    /// [`OverflowPolicy::Block`] is replaced by [`OverflowPolicy::DropNewest`].
+    pub fn spawn(self) -> ApiHandle {
+        let (tx, rx) = self.queue();
 
-    // Have remaining data to be sent
-    Remaining,
+        let overflow_policy = match self.overflow_policy {
+            OverflowPolicy::Block => {
+                diag::warn!(
+                    "OverflowPolicy::Block would block the runtime, dropping newest traces instead"
+                );
+                OverflowPolicy::DropNewest
+            }
+            policy => policy,
+        };
 
-    // Finished, either success or failed
-    Finished,
-}
+        let handle = ApiHandle {
+            channel: tx,
+            receiver: rx.clone(),
+            overflow_policy,
+            stats: self.stats(),
+        };
 
-struct Service<'a, T: Sendable> {
-    data: &'a [T],
-    // number of items to send each request,
-    batch_len: usize,
-    retry_count: u32,
-}
+        tokio::spawn(self.run(rx));
 
-impl<'a, T: Sendable> Service<'a, T> {
-    fn new(data: &'a [T]) -> Self {
-        Service {
-            batch_len: data.len(),
-            data,
-            retry_count: 0,
-        }
+        handle
     }
 
-    async fn send(&mut self, api: &Api) -> ServiceStatus {
-        // nothing to send
-        if self.data.is_empty() {
-            return ServiceStatus::Finished;
+    // the reporting loop, flushing on batch sizes, `flush_interval` and flush messages
+    pub(crate) async fn run(mut self, receiver: Receiver<Message>) {
+        if let Err(error) = self.replay_dead_letters().await {
+            diag::warn!("failed to replay dead letters: {error}");
         }
 
-        let (left, right) = self.data.split_at(self.batch_len);
+        loop {
+            let message = match self.flush_deadline() {
+                Some(deadline) => match timeout_at(deadline, receiver.recv_async()).await {
+                    Ok(message) => message.ok(),
+                    Err(_) => {
+                        // errors are already logged by `flush`
+                        let _ = self.flush().await;
+                        continue;
+                    }
+                },
+                None => receiver.recv_async().await.ok(),
+            };
+
+            match message {
+                Some(Message::Push(logs, spans)) => self.push(logs, spans).await,
+                Some(Message::Flush(request)) => {
+                    let shutdown = request.shutdown;
+                    self.reply(request).await;
 
-        let res = match T::build_request(left, api).send().await {
-            Ok(res) => res,
-            Err(error) => {
-                log::warn!("request failed with: {error}, retrying");
-                return self.retry(None);
+                    if shutdown {
+                        return reject(receiver).await;
+                    }
+                }
+                None => break,
             }
+        }
+
+        // errors are already logged by `flush`
+        let _ = self.flush().await;
+    }
+
+    async fn reply(&mut self, request: FlushRequest) {
+        let result = match request.deadline {
+            Some(deadline) => match timeout(deadline, self.drain()).await {
+                Ok(result) => result,
+                Err(_) => Err(self.abandon(FlushError::Timeout {
+                    deadline,
+                    dropped: self.queued(),
+                })),
+            },
+            None => self.drain().await,
         };
 
-        let status = res.status().as_u16();
+        let _ = request.reply.send(result);
+    }
 
-        // https://docs.newrelic.com/docs/distributed-tracing/trace-api/trace-api-general-requirements-limits#status-codes
-        match status {
-            // success
-            200..=299 => {
-                log::debug!(
-                    "recevied {} response, sent={}, remaining={}",
-                    status,
-                    left.len(),
-                    right.len(),
-                );
+    // flushes, counting what this flush delivered
+    async fn drain(&mut self) -> Result<FlushStats, FlushError> {
+        let before = FlushStats::from(self.stats.counters());
 
-                // reset retry_count
-                self.retry_count = 0;
+        self.flush().await?;
 
-                self.data = right;
+        let after = FlushStats::from(self.stats.counters());
 
-                if self.data.is_empty() {
-                    ServiceStatus::Finished
-                } else {
-                    ServiceStatus::Remaining
-                }
+        Ok(FlushStats {
+            logs_sent: after.logs_sent - before.logs_sent,
+            spans_sent: after.spans_sent - before.spans_sent,
+            metrics_sent: after.metrics_sent - before.metrics_sent,
+            events_sent: after.events_sent - before.events_sent,
+        })
+    }
+
+    fn record_span_durations(&mut self, traces: &NewrSpans) {
+        for span in &traces.spans {
+            let attributes = &span.attributes.0;
+
+            if let (Some(Value::String(name)), Some(Value::F64(duration_ms))) =
+                (attributes.get("name"), attributes.get("duration.ms"))
+            {
+                self.span_durations
+                    .entry(name.clone())
+                    .and_modify(|summary| summary.record(*duration_ms))
+                    .or_insert_with(|| DurationSummary::new(*duration_ms));
             }
+        }
+
+        if !self.span_durations.is_empty() {
+            self.open_window();
+        }
+    }
 
-            400 | 401 | 403 | 404 | 405 | 409 | 410 | 411 => {
-                log::info!("recevied {} response", status);
+    // moves numeric fields with a counter prefix out of logs
+    fn record_counters(&mut self, logs: &mut NewrLogs) {
+        for log in &mut logs.logs {
+            let attributes = &mut log.attributes.0;
 
-                ServiceStatus::Finished
+            let keys: Vec<String> = attributes
+                .iter()
+                .filter(|(key, value)| {
+                    matches!(value, Value::I64(_) | Value::U64(_) | Value::F64(_))
+                        && self
+                            .counter_prefixes
+                            .iter()
+                            .any(|prefix| key.starts_with(prefix.as_str()))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for key in keys {
+                let increment = match attributes.remove(&key) {
+                    Some(Value::I64(i)) => i as f64,
+                    Some(Value::U64(u)) => u as f64,
+                    Some(Value::F64(f)) => f,
+                    _ => continue,
+                };
+
+                let prefix_len = self
+                    .counter_prefixes
+                    .iter()
+                    .filter(|prefix| key.starts_with(prefix.as_str()))
+                    .map(String::len)
+                    .max()
+                    .unwrap_or_default();
+
+                *self
+                    .counters
+                    .entry(key[prefix_len..].to_owned())
+                    .or_default() += increment;
             }
+        }
+
+        if !self.counters.is_empty() {
+            self.open_window();
+        }
+    }
+
+    // starts the window of aggregated metrics, unless already started
+    fn open_window(&mut self) {
+        self.window_since
+            .get_or_insert_with(|| (utils::now(), Instant::now()));
+    }
+
+    // moves the metrics aggregated since the last flush into the metrics queue, as one batch
+    // https://docs.newrelic.com/docs/data-apis/ingest-apis/metric-api/report-metrics-metric-api/
+    fn queue_aggregates(&mut self) {
+        let (since, started) = match self.window_since.take() {
+            Some(window) => window,
+            None => return,
+        };
+
+        let interval_ms = started.elapsed().as_millis() as u64;
+
+        let durations = self.span_durations.drain().map(|(name, summary)| {
+            let mut metric = NewrMetric::summary("span.duration".into(), &summary, since);
+            metric.attributes.insert("span.name", name);
+            metric
+        });
+
+        let counters = self
+            .counters
+            .drain()
+            .map(|(name, count)| NewrMetric::count(name, count.into(), since));
+
+        // gauges are points in time, sent as recorded
+        let metrics = self
+            .gauges
+            .drain(..)
+            .chain(durations)
+            .chain(counters)
+            .collect();
+
+        let mut attributes = NewrAttributes::default();
+
+        if let Some(service_name) = &self.service_name {
+            attributes.insert("service.name", service_name.as_str());
+        }
+
+        if let Some(host_name) = &self.host_name {
+            attributes.insert("host.name", host_name.as_str());
+        }
+
+        for (key, value) in &self.resource_attributes {
+            attributes.insert(key, value.clone());
+        }
 
-            // 	The payload was too big.
-            413 => {
-                log::debug!("recevied 413 response, splitting payload");
+        self.metrics_queue.push(NewrMetrics {
+            metrics,
+            common: NewrMetricsCommon {
+                timestamp: since,
+                interval_ms,
+                attributes,
+            },
+        });
+    }
 
-                if self.batch_len == 1 {
-                    log::info!("dropping paylod");
+    // merges queued entries of the same trace sharing their common attributes
+    fn coalesce_spans(&mut self) {
+        // indices of the entries of each trace
+        let mut traces: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut coalesced: Vec<NewrSpans> = Vec::with_capacity(self.spans_queue.len());
 
-                    ServiceStatus::Finished
-                } else {
-                    self.batch_len %= 2;
-                    ServiceStatus::Remaining
+        for entry in self.spans_queue.drain(..) {
+            let trace_id = match entry.trace_id() {
+                Some(trace_id) => trace_id.to_owned(),
+                None => {
+                    coalesced.push(entry);
+                    continue;
                 }
-            }
+            };
 
-            // The request rate quota has been exceeded.
-            429 => {
-                let seconds = res
-                    .headers()
-                    .get("retry-after")
-                    .and_then(|val| val.to_str().ok())
-                    .and_then(|val| val.parse::<u64>().ok());
+            let indices = traces.entry(trace_id).or_default();
 
-                match seconds {
-                    Some(s) => {
-                        log::debug!("recevied 429 response, retry after {} seconds", s);
-                        ServiceStatus::Timeount(Duration::from_secs(s))
-                    }
-                    None => {
-                        log::debug!("recevied 429 response, but `retry-after` not persent");
-                        ServiceStatus::Finished
-                    }
+            match indices
+                .iter()
+                .find(|&&i| coalesced[i].common.attributes == entry.common.attributes)
+            {
+                Some(&i) => coalesced[i].spans.extend(entry.spans),
+                None => {
+                    indices.push(coalesced.len());
+                    coalesced.push(entry);
                 }
             }
+        }
+
+        self.spans_queue = coalesced;
+    }
+
+    fn queued(&self) -> usize {
+        NewrLogs::count(&self.logs_queue)
+            + NewrSpans::count(&self.spans_queue)
+            + NewrMetrics::count(&self.metrics_queue)
+            + NewrEvent::count(&self.events_queue)
+    }
+
+    // drops queued data after an interrupted flush, persisting it if possible
+    fn abandon(&mut self, error: FlushError) -> FlushError {
+        diag::error!("failed to flush: {error}");
+
+        self.write_dead_letter(&error, Signal::Logs, &self.logs_queue);
+        self.write_dead_letter(&error, Signal::Spans, &self.spans_queue);
+        self.write_dead_letter(&error, Signal::Metrics, &self.metrics_queue);
+        self.write_dead_letter(&error, Signal::Events, &self.events_queue);
+
+        self.report_error(&error);
+
+        self.logs_queue.clear();
+        self.spans_queue.clear();
+        self.metrics_queue.clear();
+        self.events_queue.clear();
+        self.buffered_since = None;
+
+        self.stats
+            .counters()
+            .queue_depth
+            .store(0, Ordering::Relaxed);
+
+        error
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<(), FlushError> {
+        self.queue_aggregates();
+
+        if self.logs_queue.is_empty()
+            && self.spans_queue.is_empty()
+            && self.metrics_queue.is_empty()
+            && self.events_queue.is_empty()
+        {
+            return Ok(());
+        }
+
+        diag::debug!(
+            "flushing logs and traces, logs_queue_len={}, spans_queue_len={}, metrics_queue_len={}, events_queue_len={}",
+            self.logs_queue.len(),
+            self.spans_queue.len(),
+            self.metrics_queue.len(),
+            self.events_queue.len(),
+        );
+
+        if self.coalesce_traces {
+            self.coalesce_spans();
+        }
+
+        // serialized once, then shared by retries and mirrors
+        let encoded_logs = self.encode_all(&self.logs_queue);
+        let encoded_spans = self.encode_all(&self.spans_queue);
+        let encoded_metrics = self.encode_all(&self.metrics_queue);
+        let encoded_events = self.encode_all(&self.events_queue);
+
+        let (mut logs, mut traces, mut metrics, mut events) = join!(
+            self.send_all(&self.logs_queue, &encoded_logs),
+            self.send_all(&self.spans_queue, &encoded_spans),
+            self.send_all(&self.metrics_queue, &encoded_metrics),
+            self.send_all(&self.events_queue, &encoded_events)
+        );
+
+        let logs_error = self.finish(&mut logs, Signal::Logs);
+        let trace_error = self.finish(&mut traces, Signal::Spans);
+        let metric_error = self.finish(&mut metrics, Signal::Metrics);
+        let event_error = self.finish(&mut events, Signal::Events);
+
+        diag::info!(
+            "flushed logs and traces, logs_queue_len={}, spans_queue_len={}, metrics_queue_len={}, events_queue_len={}",
+            self.logs_queue.len(),
+            self.spans_queue.len(),
+            self.metrics_queue.len(),
+            self.events_queue.len(),
+        );
+
+        self.logs_queue.clear();
+        self.spans_queue.clear();
+        self.metrics_queue.clear();
+        self.events_queue.clear();
+        self.buffered_since = None;
+
+        self.stats
+            .counters()
+            .queue_depth
+            .store(0, Ordering::Relaxed);
+
+        match logs_error.or(trace_error).or(metric_error).or(event_error) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn encode_all<T: Sendable>(&self, data: &[T]) -> Vec<io::Result<Vec<u8>>> {
+        data.iter().map(|item| item.encode(self)).collect()
+    }
+
+    // splits `data` into sub-batches and sends up to `max_concurrency` of them at once
+    async fn send_all<'a, T: Sendable>(
+        &'a self,
+        data: &'a [T],
+        encoded: &'a [io::Result<Vec<u8>>],
+    ) -> Vec<Service<'a, T>> {
+        let mut services = Vec::new();
+        let mut rest = Service::new(data, encoded);
+
+        while !rest.data.is_empty() {
+            let len = rest.chunk_len(self);
+
+            services.push(Service::new(&rest.data[..len], &rest.encoded[..len]));
+
+            for mirror in &self.mirrors {
+                let mut service = Service::new(&rest.data[..len], &rest.encoded[..len]);
+                service.mirror = Some(mirror);
+                services.push(service);
+            }
+
+            rest.advance(len);
+        }
+
+        // collected first, as a lazy closure would make the future not `Send`
+        let futures: Vec<_> = services
+            .into_iter()
+            .map(|service| service.drive(self))
+            .collect();
+
+        stream::iter(futures)
+            .buffer_unordered(self.max_concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    // handles the errors of finished services, returning the first one
+    fn finish<T: Sendable + Serialize>(
+        &self,
+        services: &mut [Service<'_, T>],
+        signal: Signal,
+    ) -> Option<FlushError> {
+        let mut first = None;
+
+        for service in services {
+            let error = match service.error.take() {
+                Some(error) => error,
+                None => continue,
+            };
 
-            _ => {
-                self.retry(Some(status))
+            self.stats
+                .counters()
+                .batches_failed
+                .fetch_add(1, Ordering::Relaxed);
+
+            // dead letters are only replayed to the primary destination
+            if service.mirror.is_none() {
+                self.write_dead_letter(&error, signal, service.data);
             }
+
+            diag::error!("failed to send {}: {error}", signal.name());
+            self.report_error(&error);
+
+            first = first.or(Some(error));
         }
+
+        first
     }
 
-    fn retry(&mut self, status: Option<u16>) -> ServiceStatus {
-        let status = status.map(|status| status.to_string()).unwrap_or_else(|| "<none>".to_owned());
-
-        if self.retry_count == 0 {
-            log::info!(
-                        "recevied {} response, retry immediately, retry_count={}",
-                        status,
-                        self.retry_count,
-                    );
-            self.retry_count += 1;
-            ServiceStatus::Timeount(Duration::from_secs(0))
-        } else if self.retry_count <= 5 {
-            let s = 2_u64.pow(self.retry_count - 1_u32); // 2^n
-            log::info!(
-                        "recevied {} response, retry after {} seconds, retry_count={}",
-                        status,
-                        s,
-                        self.retry_count,
-                    );
-            self.retry_count += 1;
-            ServiceStatus::Timeount(Duration::from_secs(s))
-        } else {
-            log::info!("recevied {} response, reached max retry count", status);
-            ServiceStatus::Finished
+    // base * factor^n, randomized by jitter, capped at `retry_max`
+    fn retry_delay(&self, retry_count: u32) -> Duration {
+        let exponent = i32::try_from(retry_count).unwrap_or(i32::MAX);
+        let mut secs = self.retry_base.as_secs_f64() * self.retry_factor.powi(exponent);
+
+        if self.jitter {
+            secs *= 0.5 + fastrand::f64();
+        }
+
+        // also guards against negative, NaN or overflowing delays of unusual settings
+        Duration::try_from_secs_f64(secs.max(0.0))
+            .map_or(self.retry_max, |delay| delay.min(self.retry_max))
+    }
+
+    // batch sizes of logs and spans, honoring the deprecated `batch_size`
+    #[allow(deprecated)]
+    fn batch_sizes(&self) -> (usize, usize) {
+        match self.batch_size {
+            0 => (self.log_batch_size, self.span_batch_size),
+            batch_size => (batch_size, batch_size),
         }
     }
-}
 
-trait Sendable {
-    fn build_request(data: &[Self], api: &Api) -> RequestBuilder
-    where
-        Self: Sized;
-}
+    /// The channel of traces waiting to be sent
+    pub(crate) fn queue(&self) -> (Sender<Message>, Receiver<Message>) {
+        // a rendezvous channel would drop every trace, as the layer never waits for the worker
+        if self.max_queue_len == 0 {
+            diag::warn!("max_queue_len is zero, queueing a single trace instead");
+        }
 
-impl Sendable for NewrLogs {
-    fn build_request(data: &[NewrLogs], api: &Api) -> RequestBuilder {
-        let url = match &api.log_endpoint {
-            ApiEndpoint::US => "https://log-api.newrelic.com/log/v1".into(),
-            ApiEndpoint::EU => "https://log-api.eu.newrelic.com/log/v1".into(),
-            ApiEndpoint::Custom(domain) => format!("{domain}/log/v1"),
-        };
-        // https://docs.newrelic.com/docs/logs/log-api/introduction-log-api/#json-headers
-        api.client
-            .post(url)
-            .header(CONTENT_TYPE, "application/json")
-            .header(CONTENT_ENCODING, "gzip")
-            .header("Api-Key", &api.key)
-            .body(to_gz(&data))
+        flume::bounded(self.max_queue_len.max(1))
     }
-}
 
-impl Sendable for NewrSpans {
-    fn build_request(data: &[NewrSpans], api: &Api) -> RequestBuilder {
-        let url = match &api.log_endpoint {
-            ApiEndpoint::US => "https://trace-api.newrelic.com/trace/v1".into(),
-            ApiEndpoint::EU => "https://trace-api.eu.newrelic.com/trace/v1".into(),
-            ApiEndpoint::Custom(domain) => format!("{domain}/trace/v1"),
-        };
-        // https://docs.newrelic.com/docs/distributed-tracing/trace-api/trace-api-general-requirements-limits/#headers-query-parameters
-        api.client
-            .post(&url)
-            .header(CONTENT_TYPE, "application/json")
-            .header(CONTENT_ENCODING, "gzip")
-            .header("Api-Key", &api.key)
-            .header("Data-Format", "newrelic")
-            .header("Data-Format-Version", "1")
-            .body(to_gz(&data))
+    /// When buffered data should be flushed regardless of batch sizes
+    pub(crate) fn flush_deadline(&self) -> Option<Instant> {
+        self.buffered_since
+            .map(|instant| instant + self.flush_interval)
     }
-}
 
-#[inline]
-fn to_gz<T: Serialize>(data: T) -> Vec<u8> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-    serde_json::to_writer(&mut encoder, &data).unwrap();
-    encoder.finish().unwrap()
+    /// Sends an empty payload to the log and trace endpoints, e.g. to fail fast on startup
+    ///
+    /// Returns the first authentication, connectivity or server error, or a rejection
+    /// showing the endpoint doesn't exist, e.g. 404 on a wrong path.
+    ///
+    /// Requires a tokio runtime.
+    pub async fn verify(&self) -> Result<(), ApiError> {
+        for signal in &[Signal::Logs, Signal::Spans] {
+            let request = signal.request(self, None).body("[]");
+            let res = self.execute(request).await?;
+            let status = res.status().as_u16();
+
+            match ApiError::from_status(status, None) {
+                // the endpoint was reached with an accepted key, only the payload was refused
+                None | Some(ApiError::Rejected(400)) | Some(ApiError::PayloadTooLarge) => {
+                    diag::debug!("verified {} endpoint, status={}", signal.name(), status);
+                }
+                Some(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    // state of the destination `signal` is sent to
+    fn target(&self, signal: Signal, mirror: Option<&Mirror>) -> Arc<Target> {
+        self.targets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(signal.url(self, mirror))
+            .or_default()
+            .clone()
+    }
+
+    // created on first use, as `max_in_flight` may be set after construction
+    fn in_flight(&self) -> Option<&Semaphore> {
+        let max_in_flight = self.max_in_flight?;
+        Some(
+            self.in_flight
+                .get_or_init(|| Semaphore::new(max_in_flight.max(1))),
+        )
+    }
+
+    async fn execute(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        match &self.transport {
+            Some(transport) => match request.build() {
+                Ok(request) => transport.send(request).await,
+                Err(error) => Err(ApiError::Network(error)),
+            },
+            None => request.send().await.map_err(ApiError::Network),
+        }
+    }
+
+    /// Re-sends the batches persisted in `dead_letter_dir`
+    ///
+    /// Files are only deleted once they have been delivered.
+    ///
+    /// Requires a tokio runtime.
+    pub async fn replay_dead_letters(&mut self) -> Result<(), FlushError> {
+        let dir = match &self.dead_letter_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+                .map_err(FlushError::DeadLetter)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(FlushError::DeadLetter(error)),
+        };
+
+        // file names start with the signal and timestamp
+        paths.sort();
+
+        let mut letters = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            if let Some(letter) = DeadLetter::read(path).map_err(FlushError::DeadLetter)? {
+                letters.push(letter);
+            }
+        }
+
+        if letters.is_empty() {
+            return Ok(());
+        }
+
+        diag::debug!("replaying dead letters, len={}", letters.len());
+
+        let encoded = self.encode_all(&letters);
+        let mut service = Service::new(&letters, &encoded);
+        service.batch_len = 1;
+
+        let mut service = service.drive(self).await;
+
+        let delivered = letters.len() - service.data.len();
+
+        for letter in &letters[..delivered] {
+            fs::remove_file(&letter.path).map_err(FlushError::DeadLetter)?;
+        }
+
+        diag::info!(
+            "replayed dead letters, delivered={}, remaining={}",
+            delivered,
+            letters.len() - delivered,
+        );
+
+        match service.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn write_dead_letter<T: Serialize>(&self, error: &FlushError, signal: Signal, data: &[T]) {
+        let dir = match &self.dead_letter_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        // rejected payloads are not going to be accepted later either
+        if !matches!(
+            error,
+            FlushError::Network { .. }
+                | FlushError::RetriesExhausted { .. }
+                | FlushError::CircuitOpen { .. }
+                | FlushError::Timeout { .. }
+        ) {
+            return;
+        }
+
+        if data.is_empty() {
+            return;
+        }
+
+        match DeadLetter::write(dir, signal, data, self.compression) {
+            Ok(path) => diag::info!("persisted dropped {} to {}", signal.name(), path.display()),
+            Err(error) => diag::error!("failed to persist dropped {}: {error}", signal.name()),
+        }
+    }
+
+    fn inspect_payload(&self, signal: Signal, json: &[u8]) {
+        if let Some(on_payload) = &self.on_payload {
+            on_payload(signal, json);
+        }
+    }
+
+    fn report_error(&self, error: &FlushError) {
+        if let Some(on_error) = &self.on_error {
+            on_error(error);
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            log_endpoint: ApiEndpoint::default(),
+            trace_endpoint: ApiEndpoint::default(),
+            metric_endpoint: ApiEndpoint::default(),
+            event_endpoint: ApiEndpoint::default(),
+            account_id: None,
+            insert_key: None,
+            key: String::new(),
+            trace_key: None,
+            client: default_client()
+                .build()
+                .expect("failed to build the http client"),
+            transport: None,
+            log_path: None,
+            trace_path: None,
+            metric_path: None,
+            mirrors: Vec::new(),
+            extra_headers: HeaderMap::new(),
+            request_timeout: Duration::from_secs(10),
+            log_batch_size: 10,
+            span_batch_size: 10,
+            batch_size: 0,
+            max_queue_len: 1000,
+            overflow_policy: OverflowPolicy::default(),
+            log_format: LogFormat::default(),
+            max_payload_bytes: 1_000_000,
+            flush_interval: Duration::from_secs(5),
+            max_concurrency: 4,
+            max_requests_per_second: None,
+            adaptive_batch: None,
+            max_in_flight: None,
+            logs_enabled: true,
+            traces_enabled: true,
+            metrics_enabled: true,
+            dry_run: false,
+            compress: true,
+            compression: Compression::fast(),
+            retry_base: Duration::from_secs(1),
+            retry_factor: 2.0,
+            retry_max: Duration::from_secs(30),
+            jitter: false,
+            max_retries: 5,
+            max_retry_elapsed: None,
+            retry_if: None,
+            circuit_threshold: None,
+            circuit_cooldown: Duration::from_secs(30),
+            on_error: None,
+            on_payload: None,
+            on_sent: None,
+            service_name: None,
+            host_name: None,
+            common_attributes: HashMap::new(),
+            resource_attributes: HashMap::new(),
+            dead_letter_dir: None,
+            span_metrics: false,
+            span_duration_metrics: false,
+            coalesce_traces: false,
+            counter_prefixes: vec!["monotonic_counter.".into(), "counter.".into()],
+            stats: ExporterStats::default(),
+            rotated_key: KeyHandle::default(),
+            targets: Mutex::default(),
+            in_flight: OnceLock::new(),
+            logs_queue: Vec::with_capacity(10),
+            spans_queue: Vec::with_capacity(10),
+            metrics_queue: Vec::with_capacity(10),
+            events_queue: Vec::with_capacity(10),
+            buffered_since: None,
+            gauges: Vec::new(),
+            span_durations: HashMap::new(),
+            counters: HashMap::new(),
+            window_since: None,
+        }
+    }
+}
+
+impl From<String> for Api {
+    fn from(key: String) -> Self {
+        Api {
+            key,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&str> for Api {
+    fn from(key: &str) -> Self {
+        Api {
+            key: key.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<(String, ApiEndpoint)> for Api {
+    fn from(t: (String, ApiEndpoint)) -> Self {
+        Api {
+            key: t.0,
+            log_endpoint: t.1.clone(),
+            trace_endpoint: t.1.clone(),
+            metric_endpoint: t.1.clone(),
+            event_endpoint: t.1,
+            ..Default::default()
+        }
+    }
+}
+
+/// Key, log endpoint and trace endpoint
+///
+/// Events go to the log endpoint and metrics to the trace endpoint,
+/// as they are generated from logs and spans respectively.
+impl From<(String, ApiEndpoint, ApiEndpoint)> for Api {
+    fn from(t: (String, ApiEndpoint, ApiEndpoint)) -> Self {
+        Api {
+            key: t.0,
+            log_endpoint: t.1.clone(),
+            trace_endpoint: t.2.clone(),
+            metric_endpoint: t.2,
+            event_endpoint: t.1,
+            ..Default::default()
+        }
+    }
+}
+
+enum ServiceStatus {
+    // Need to wait before next sending
+    Timeout(Duration),
+
+    // Have remaining data to be sent
+    Remaining,
+
+    // Finished, either success or failed
+    Finished,
+}
+
+struct Service<'a, T: Sendable> {
+    data: &'a [T],
+    // serialized items of `data`
+    encoded: &'a [io::Result<Vec<u8>>],
+    // number of items to send each request,
+    batch_len: usize,
+    retry_count: u32,
+    // when the first of the current retries failed
+    failing_since: Option<Instant>,
+    // set when the remaining data was given up on
+    error: Option<FlushError>,
+    // sends to this destination instead of the primary one
+    mirror: Option<&'a Mirror>,
+}
+
+impl<'a, T: Sendable> Service<'a, T> {
+    fn new(data: &'a [T], encoded: &'a [io::Result<Vec<u8>>]) -> Self {
+        Service {
+            batch_len: data.len(),
+            data,
+            encoded,
+            retry_count: 0,
+            failing_since: None,
+            error: None,
+            mirror: None,
+        }
+    }
+
+    // sends until either all data is sent or the rest is given up on
+    async fn drive(mut self, api: &Api) -> Self {
+        loop {
+            match self.send(api).await {
+                ServiceStatus::Timeout(d) => sleep(d).await,
+                ServiceStatus::Remaining => {}
+                ServiceStatus::Finished => return self,
+            }
+        }
+    }
+
+    async fn send(&mut self, api: &Api) -> ServiceStatus {
+        // nothing to send
+        if self.data.is_empty() || self.error.is_some() {
+            return ServiceStatus::Finished;
+        }
+
+        let (left, right) = self.data.split_at(self.chunk_len(api));
+
+        let json = match to_json_array(&self.encoded[..left.len()]) {
+            Ok(json) => json,
+            Err(error) => {
+                diag::warn!("failed to encode payload: {error}");
+                let error = ApiError::Serialization(error);
+                return self.handle(error, ApiResponse::default(), left, api);
+            }
+        };
+
+        if api.dry_run {
+            api.inspect_payload(T::signal(left), &json);
+            diag::info!("dry run, payload={}", String::from_utf8_lossy(&json));
+
+            self.advance(left.len());
+
+            return if self.data.is_empty() {
+                ServiceStatus::Finished
+            } else {
+                ServiceStatus::Remaining
+            };
+        }
+
+        let target = api.target(T::signal(left), self.mirror);
+
+        if target.circuit.is_open() {
+            diag::debug!("circuit breaker is open, skipping request");
+
+            return self.fail(FlushError::CircuitOpen {
+                dropped: T::count(self.data),
+            });
+        }
+
+        if let Some(wait) = target.rate_limiter.acquire(api) {
+            diag::debug!("rate limited, waiting {:?}", wait);
+            return ServiceStatus::Timeout(wait);
+        }
+
+        let request = match with_payload(T::signal(left), json, api, self.mirror) {
+            Ok(request) => request,
+            Err(error) => {
+                diag::warn!("failed to compress payload: {error}");
+                let error = ApiError::Serialization(error);
+                return self.handle(error, ApiResponse::default(), left, api);
+            }
+        };
+
+        // held until the response is read
+        let _permit = match api.in_flight() {
+            Some(in_flight) => in_flight.acquire().await.ok(),
+            None => None,
+        };
+
+        let result = api.execute(request).await;
+
+        let (error, body) = match result {
+            Ok(res) => {
+                let status = res.status().as_u16();
+
+                let retry_after = res
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|val| val.to_str().ok())
+                    .and_then(parse_retry_after);
+
+                let body = ApiResponse::read(res).await;
+
+                match ApiError::from_status(status, retry_after) {
+                    Some(error) => {
+                        diag::info!(
+                            "recevied {} response, request_id={}, errors={:?}",
+                            status,
+                            body.request_id.as_deref().unwrap_or("<none>"),
+                            body.errors,
+                        );
+                        (error, body)
+                    }
+                    None => {
+                        diag::debug!(
+                            "recevied {} response, sent={}, remaining={}, request_id={}",
+                            status,
+                            left.len(),
+                            right.len(),
+                            body.request_id.as_deref().unwrap_or("<none>"),
+                        );
+                        return self.sent(body, left, api);
+                    }
+                }
+            }
+            Err(error) => {
+                match &error {
+                    ApiError::Network(error) if error.is_timeout() => {
+                        diag::warn!("request timed out after {:?}", api.request_timeout);
+                    }
+                    error => diag::warn!("{error}"),
+                }
+                (error, ApiResponse::default())
+            }
+        };
+
+        self.handle(error, body, left, api)
+    }
+
+    fn sent(&mut self, body: ApiResponse, left: &[T], api: &Api) -> ServiceStatus {
+        if !body.warnings.is_empty() {
+            let error = FlushError::Warnings {
+                request_id: body.request_id.clone(),
+                warnings: body.warnings,
+            };
+
+            diag::warn!("{error}");
+            api.report_error(&error);
+        }
+
+        // reset retry_count
+        self.retry_count = 0;
+        self.failing_since = None;
+
+        let target = api.target(T::signal(left), self.mirror);
+        target.circuit.record_success();
+        target.adaptive_len.grow(api);
+
+        if let Some(on_sent) = &api.on_sent {
+            on_sent(T::signal(left), body.request_id.as_deref());
+        }
+
+        // mirrored data would be counted twice
+        if self.mirror.is_none() {
+            T::record_sent(left, api.stats.counters());
+        }
+
+        self.advance(left.len());
+
+        if self.data.is_empty() {
+            ServiceStatus::Finished
+        } else {
+            ServiceStatus::Remaining
+        }
+    }
+
+    // https://docs.newrelic.com/docs/distributed-tracing/trace-api/trace-api-general-requirements-limits#status-codes
+    fn handle(
+        &mut self,
+        error: ApiError,
+        body: ApiResponse,
+        left: &[T],
+        api: &Api,
+    ) -> ServiceStatus {
+        let overloaded = match &error {
+            ApiError::RateLimited { .. } | ApiError::Server(_) | ApiError::PayloadTooLarge => true,
+            // smaller batches are handled faster
+            ApiError::Network(error) => error.is_timeout(),
+            _ => false,
+        };
+
+        if overloaded {
+            api.target(T::signal(left), self.mirror)
+                .adaptive_len
+                .shrink(api);
+        }
+
+        let retryable = match &api.retry_if {
+            Some(retry_if) => retry_if(&error),
+            None => error.is_retryable(),
+        };
+
+        if retryable {
+            return self.retry(error, body, api);
+        }
+
+        // The payload was too big.
+        if let ApiError::PayloadTooLarge = error {
+            if left.len() > 1 {
+                diag::debug!("recevied 413 response, splitting payload");
+
+                // the remaining data is sent in halves of the rejected chunk
+                self.batch_len = left.len() / 2;
+                return ServiceStatus::Remaining;
+            }
+
+            // a single item can't be split any further, drop only that one
+            let error = FlushError::Rejected {
+                status: 413,
+                errors: body.errors,
+                dropped: T::count(left),
+            };
+
+            diag::error!("dropping oversized {}: {error}", T::signal(left).name());
+
+            api.stats
+                .counters()
+                .batches_failed
+                .fetch_add(1, Ordering::Relaxed);
+            api.report_error(&error);
+
+            self.advance(left.len());
+
+            return if self.data.is_empty() {
+                ServiceStatus::Finished
+            } else {
+                ServiceStatus::Remaining
+            };
+        }
+
+        self.give_up(error, body, api)
+    }
+
+    // number of items to send in the next request, keeping the
+    // serialized payload under `max_payload_bytes`
+    fn chunk_len(&self, api: &Api) -> usize {
+        // the enclosing brackets
+        let mut size = 2;
+        let mut len = 0;
+
+        let target = api.target(T::signal(self.data), self.mirror);
+        let batch_len = self.batch_len.min(target.adaptive_len.get(api));
+
+        for item in self.encoded.iter().take(batch_len) {
+            // the item and its separating comma, failures are reported once sending
+            let item_size = item.as_ref().map_or(0, Vec::len) + 1;
+
+            // always send at least one item
+            if len > 0 && size + item_size > api.max_payload_bytes {
+                break;
+            }
+
+            size += item_size;
+            len += 1;
+        }
+
+        len
+    }
+
+    fn retry(&mut self, error: ApiError, body: ApiResponse, api: &Api) -> ServiceStatus {
+        let failing_since = *self.failing_since.get_or_insert_with(Instant::now);
+
+        if let Some(max_retry_elapsed) = api.max_retry_elapsed {
+            if failing_since.elapsed() >= max_retry_elapsed {
+                diag::info!("{error}, reached max retry elapsed time");
+                return self.give_up(error, body, api);
+            }
+        }
+
+        if self.retry_count >= api.max_retries {
+            diag::info!("{error}, reached max retry count");
+            return self.give_up(error, body, api);
+        }
+
+        let delay = match error {
+            // The request rate quota has been exceeded, a far-future
+            // `Retry-After` mustn't stall the reporter
+            ApiError::RateLimited {
+                retry_after: Some(retry_after),
+            } => retry_after.min(api.retry_max),
+            _ => api.retry_delay(self.retry_count),
+        };
+
+        diag::info!(
+            "{error}, retry after {:?}, retry_count={}",
+            delay,
+            self.retry_count,
+        );
+        self.retry_count += 1;
+        api.stats.counters().retries.fetch_add(1, Ordering::Relaxed);
+        ServiceStatus::Timeout(delay)
+    }
+
+    fn give_up(&mut self, error: ApiError, body: ApiResponse, api: &Api) -> ServiceStatus {
+        let dropped = T::count(self.data);
+
+        // failing to encode says nothing about the endpoint
+        if !matches!(error, ApiError::Serialization(_)) {
+            api.target(T::signal(self.data), self.mirror)
+                .circuit
+                .record_failure(api);
+        }
+
+        self.fail(match error {
+            ApiError::Network(error) => FlushError::Network { error, dropped },
+            ApiError::Serialization(error) => FlushError::Encode { error, dropped },
+            ApiError::Unauthorized(status) => FlushError::Unauthorized { status, dropped },
+            ApiError::PayloadTooLarge => FlushError::Rejected {
+                status: 413,
+                errors: body.errors,
+                dropped,
+            },
+            ApiError::Rejected(status) => FlushError::Rejected {
+                status,
+                errors: body.errors,
+                dropped,
+            },
+            ApiError::RateLimited { .. } => FlushError::RetriesExhausted {
+                status: 429,
+                retries: self.retry_count,
+                dropped,
+            },
+            ApiError::Server(status) => FlushError::RetriesExhausted {
+                status,
+                retries: self.retry_count,
+                dropped,
+            },
+        })
+    }
+
+    // moves past the first `len` items, once sent or dropped
+    fn advance(&mut self, len: usize) {
+        self.data = &self.data[len..];
+        self.encoded = &self.encoded[len..];
+    }
+
+    fn fail(&mut self, error: FlushError) -> ServiceStatus {
+        self.error = Some(error);
+        ServiceStatus::Finished
+    }
+}
+
+// state of a destination of a signal, so that a failing or throttling
+// destination doesn't affect the others
+#[derive(Default)]
+struct Target {
+    circuit: Circuit,
+    rate_limiter: RateLimiter,
+    adaptive_len: AdaptiveLen,
+}
+
+// stops sending while the endpoint keeps failing
+#[derive(Default)]
+struct Circuit {
+    // consecutive failed batches
+    failures: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl Circuit {
+    fn is_open(&self) -> bool {
+        let open_until = self
+            .open_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // once the cooldown elapsed, requests are let through as probes
+        matches!(*open_until, Some(until) if Instant::now() < until)
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        *self
+            .open_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    fn record_failure(&self, api: &Api) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let threshold = match api.circuit_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        if failures >= threshold {
+            diag::warn!(
+                "opening circuit breaker for {:?}, failures={}",
+                api.circuit_cooldown,
+                failures
+            );
+
+            *self
+                .open_until
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) =
+                Some(Instant::now() + api.circuit_cooldown);
+        }
+    }
+}
+
+// number of items per request adapted to backpressure, kept across flushes
+#[derive(Default)]
+struct AdaptiveLen {
+    // zero until the first request
+    len: AtomicUsize,
+}
+
+impl AdaptiveLen {
+    fn get(&self, api: &Api) -> usize {
+        match api.adaptive_batch {
+            Some((min, max)) => match self.len.load(Ordering::Relaxed) {
+                0 => max.max(1),
+                len => len.clamp(min.max(1), max.max(1)),
+            },
+            None => usize::MAX,
+        }
+    }
+
+    fn grow(&self, api: &Api) {
+        if let Some((_, max)) = api.adaptive_batch {
+            let len = (self.get(api) + 1).min(max.max(1));
+            self.len.store(len, Ordering::Relaxed);
+        }
+    }
+
+    fn shrink(&self, api: &Api) {
+        if let Some((min, _)) = api.adaptive_batch {
+            let len = (self.get(api) / 2).max(min.max(1));
+            diag::debug!("shrinking batches to {} items", len);
+            self.len.store(len, Ordering::Relaxed);
+        }
+    }
+}
+
+// token bucket kept across flushes, allowing bursts of
+// up to a second worth of requests
+#[derive(Default)]
+struct RateLimiter {
+    // available tokens and when they were last refilled
+    bucket: Mutex<Option<(f64, Instant)>>,
+}
+
+impl RateLimiter {
+    // takes a token, or returns how long to wait for the next one
+    fn acquire(&self, api: &Api) -> Option<Duration> {
+        let rate = match api.max_requests_per_second {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return None,
+        };
+
+        let capacity = rate.max(1.0);
+        let now = Instant::now();
+
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let (tokens, refilled_at) = bucket.get_or_insert((capacity, now));
+
+        *tokens = (*tokens + now.duration_since(*refilled_at).as_secs_f64() * rate).min(capacity);
+        *refilled_at = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - *tokens) / rate))
+        }
+    }
+}
+
+// body of New Relic responses, all fields are optional depending on the Api
+#[derive(Default, Deserialize)]
+struct ApiResponse {
+    #[serde(rename = "requestId")]
+    request_id: Option<String>,
+    #[serde(default, deserialize_with = "messages")]
+    errors: Vec<String>,
+    #[serde(default, deserialize_with = "messages")]
+    warnings: Vec<String>,
+}
+
+impl ApiResponse {
+    // a missing or unexpected body isn't an error, the status code is what matters
+    async fn read(res: Response) -> ApiResponse {
+        match res.bytes().await {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Err(_) => ApiResponse::default(),
+        }
+    }
+}
+
+// errors and warnings are either plain strings or objects
+fn messages<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let values = Vec::<serde_json::Value>::deserialize(deserializer)?;
+
+    Ok(values
+        .into_iter()
+        .map(|value| match value {
+            serde_json::Value::String(message) => message,
+            value => value.to_string(),
+        })
+        .collect())
+}
+
+trait Sendable {
+    fn signal(data: &[Self]) -> Signal
+    where
+        Self: Sized;
+
+    // this item as sent, the elements it adds to the payload array
+    fn encode(&self, api: &Api) -> io::Result<Vec<u8>>;
+
+    // number of logs, spans, metrics or events, e.g. reported as dropped
+    fn count(data: &[Self]) -> usize
+    where
+        Self: Sized;
+
+    // counts delivered items
+    fn record_sent(data: &[Self], counters: &Counters)
+    where
+        Self: Sized;
+}
+
+impl Sendable for NewrLogs {
+    fn signal(_: &[NewrLogs]) -> Signal {
+        Signal::Logs
+    }
+
+    fn encode(&self, api: &Api) -> io::Result<Vec<u8>> {
+        match api.log_format {
+            LogFormat::Detailed => to_json_vec(self),
+            LogFormat::Simple => {
+                let logs: Vec<_> = self.simple().collect();
+                array_elements(to_json_vec(&logs)?)
+            }
+        }
+    }
+
+    fn count(data: &[NewrLogs]) -> usize {
+        data.iter().map(|logs| logs.logs.len()).sum()
+    }
+
+    fn record_sent(data: &[NewrLogs], counters: &Counters) {
+        let n = NewrLogs::count(data);
+        counters.logs_sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Sendable for NewrSpans {
+    fn signal(_: &[NewrSpans]) -> Signal {
+        Signal::Spans
+    }
+
+    fn encode(&self, _: &Api) -> io::Result<Vec<u8>> {
+        to_json_vec(self)
+    }
+
+    fn count(data: &[NewrSpans]) -> usize {
+        data.iter().map(|spans| spans.spans.len()).sum()
+    }
+
+    fn record_sent(data: &[NewrSpans], counters: &Counters) {
+        let n = NewrSpans::count(data);
+        counters.spans_sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Sendable for NewrMetrics {
+    fn signal(_: &[NewrMetrics]) -> Signal {
+        Signal::Metrics
+    }
+
+    fn encode(&self, _: &Api) -> io::Result<Vec<u8>> {
+        to_json_vec(self)
+    }
+
+    fn count(data: &[NewrMetrics]) -> usize {
+        data.iter().map(|metrics| metrics.metrics.len()).sum()
+    }
+
+    fn record_sent(data: &[NewrMetrics], counters: &Counters) {
+        let n = NewrMetrics::count(data);
+        counters.metrics_sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+impl Sendable for NewrEvent {
+    fn signal(_: &[NewrEvent]) -> Signal {
+        Signal::Events
+    }
+
+    fn encode(&self, _: &Api) -> io::Result<Vec<u8>> {
+        to_json_vec(self)
+    }
+
+    fn count(data: &[NewrEvent]) -> usize {
+        data.len()
+    }
+
+    fn record_sent(data: &[NewrEvent], counters: &Counters) {
+        counters
+            .events_sent
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Sendable for DeadLetter {
+    fn signal(data: &[DeadLetter]) -> Signal {
+        data[0].signal
+    }
+
+    // the persisted payload is sent again as is
+    fn encode(&self, _: &Api) -> io::Result<Vec<u8>> {
+        array_elements(from_gz(&self.body)?)
+    }
+
+    fn count(_: &[DeadLetter]) -> usize {
+        // nothing is lost, letters are only deleted once delivered
+        0
+    }
+
+    fn record_sent(_: &[DeadLetter], _: &Counters) {
+        // not counted, the number of items is unknown without decoding
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Kind of data sent to New Relic
+pub enum Signal {
+    /// Logs, sent to the Log Api
+    Logs,
+    /// Spans, sent to the Trace Api
+    Spans,
+    /// Metrics, sent to the Metric Api
+    Metrics,
+    /// Custom events, sent to the Event Api
+    Events,
+}
+
+impl Signal {
+    fn name(self) -> &'static str {
+        match self {
+            Signal::Logs => "logs",
+            Signal::Spans => "spans",
+            Signal::Metrics => "metrics",
+            Signal::Events => "events",
+        }
+    }
+
+    // builds a request without body and content encoding
+    pub(crate) fn endpoint<'a>(
+        self,
+        api: &'a Api,
+        mirror: Option<&'a Mirror>,
+    ) -> (&'a ApiEndpoint, String) {
+        match mirror {
+            Some((endpoint, key)) => (endpoint, key.clone()),
+            None => match self {
+                Signal::Logs => (&api.log_endpoint, api.current_key()),
+                Signal::Spans => (
+                    &api.trace_endpoint,
+                    api.trace_key.clone().unwrap_or_else(|| api.current_key()),
+                ),
+                Signal::Metrics => (&api.metric_endpoint, api.current_key()),
+                Signal::Events => (
+                    &api.event_endpoint,
+                    api.insert_key.clone().unwrap_or_else(|| api.current_key()),
+                ),
+            },
+        }
+    }
+
+    // url the signal is sent to, either to the primary destination or `mirror`
+    fn url(self, api: &Api, mirror: Option<&Mirror>) -> String {
+        let (endpoint, _) = self.endpoint(api, mirror);
+
+        match self {
+            Signal::Logs => match endpoint {
+                ApiEndpoint::US => "https://log-api.newrelic.com/log/v1".into(),
+                ApiEndpoint::EU => "https://log-api.eu.newrelic.com/log/v1".into(),
+                ApiEndpoint::FedRAMP => "https://gov-log-api.newrelic.com/log/v1".into(),
+                ApiEndpoint::Custom(_) | ApiEndpoint::LocalForwarder(_) => {
+                    let path = api.log_path.as_deref().unwrap_or("/log/v1");
+                    format!("{}{}", endpoint.base_url(), path)
+                }
+            },
+            Signal::Spans => match endpoint {
+                ApiEndpoint::US => "https://trace-api.newrelic.com/trace/v1".into(),
+                ApiEndpoint::EU => "https://trace-api.eu.newrelic.com/trace/v1".into(),
+                ApiEndpoint::FedRAMP => "https://gov-trace-api.newrelic.com/trace/v1".into(),
+                ApiEndpoint::Custom(_) | ApiEndpoint::LocalForwarder(_) => {
+                    let path = api.trace_path.as_deref().unwrap_or("/trace/v1");
+                    format!("{}{}", endpoint.base_url(), path)
+                }
+            },
+            Signal::Metrics => match endpoint {
+                ApiEndpoint::US => "https://metric-api.newrelic.com/metric/v1".into(),
+                ApiEndpoint::EU => "https://metric-api.eu.newrelic.com/metric/v1".into(),
+                ApiEndpoint::FedRAMP => "https://gov-metric-api.newrelic.com/metric/v1".into(),
+                ApiEndpoint::Custom(_) | ApiEndpoint::LocalForwarder(_) => {
+                    let path = api.metric_path.as_deref().unwrap_or("/metric/v1");
+                    format!("{}{}", endpoint.base_url(), path)
+                }
+            },
+            Signal::Events => {
+                // events are only collected when `account_id` is set
+                let account_id = api.account_id.as_deref().unwrap_or_default();
+                match endpoint {
+                    ApiEndpoint::US => format!(
+                        "https://insights-collector.newrelic.com/v1/accounts/{account_id}/events"
+                    ),
+                    ApiEndpoint::EU => format!(
+                        "https://insights-collector.eu01.nr-data.net/v1/accounts/{account_id}/events"
+                    ),
+                    ApiEndpoint::FedRAMP => format!(
+                        "https://gov-insights-collector.newrelic.com/v1/accounts/{account_id}/events"
+                    ),
+                    ApiEndpoint::Custom(_) | ApiEndpoint::LocalForwarder(_) => {
+                        format!("{}/v1/accounts/{account_id}/events", endpoint.base_url())
+                    }
+                }
+            }
+        }
+    }
+
+    fn request(self, api: &Api, mirror: Option<&Mirror>) -> RequestBuilder {
+        let (_, key) = self.endpoint(api, mirror);
+
+        let request = api
+            .client
+            .post(self.url(api, mirror))
+            .header(CONTENT_TYPE, "application/json");
+
+        let (request, key_header) = match self {
+            // https://docs.newrelic.com/docs/logs/log-api/introduction-log-api/#json-headers
+            Signal::Logs => (request, "Api-Key"),
+            // https://docs.newrelic.com/docs/distributed-tracing/trace-api/trace-api-general-requirements-limits/#headers-query-parameters
+            Signal::Spans => (
+                request
+                    .header("Data-Format", "newrelic")
+                    .header("Data-Format-Version", "1"),
+                "Api-Key",
+            ),
+            // https://docs.newrelic.com/docs/data-apis/ingest-apis/metric-api/report-metrics-metric-api/#headers
+            Signal::Metrics => (request, "Api-Key"),
+            // https://docs.newrelic.com/docs/data-apis/ingest-apis/event-api/introduction-event-api/#submit-event
+            Signal::Events => (request, "X-Insert-Key"),
+        };
+
+        // local forwarders may be used without a key
+        let request = if key.is_empty() {
+            request
+        } else {
+            request.header(key_header, key)
+        };
+
+        request
+            .headers(api.extra_headers.clone())
+            .timeout(api.request_timeout)
+    }
+}
+
+/// A gzipped payload that failed to be delivered, persisted in `dead_letter_dir`
+struct DeadLetter {
+    signal: Signal,
+    path: PathBuf,
+    body: Vec<u8>,
+}
+
+impl DeadLetter {
+    fn file_name(signal: Signal) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        format!("{}-{}-{}.json.gz", signal.name(), timestamp, Uuid::new_v4())
+    }
+
+    fn write<T: Serialize>(
+        dir: &Path,
+        signal: Signal,
+        data: &[T],
+        compression: Compression,
+    ) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(DeadLetter::file_name(signal));
+        fs::write(&path, to_gz(&to_json_vec(data)?, compression)?)?;
+        Ok(path)
+    }
+
+    fn read(path: PathBuf) -> io::Result<Option<DeadLetter>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if !name.ends_with(".json.gz") {
+            return Ok(None);
+        }
+
+        let signal = if name.starts_with("logs-") {
+            Signal::Logs
+        } else if name.starts_with("spans-") {
+            Signal::Spans
+        } else if name.starts_with("metrics-") {
+            Signal::Metrics
+        } else if name.starts_with("events-") {
+            Signal::Events
+        } else {
+            return Ok(None);
+        };
+
+        let body = fs::read(&path)?;
+
+        Ok(Some(DeadLetter { signal, path, body }))
+    }
+}
+
+// rejects data sent after a shutdown, until every sender is dropped
+async fn reject(receiver: Receiver<Message>) {
+    while let Ok(message) = receiver.recv_async().await {
+        match message {
+            Message::Push(..) => diag::warn!("exporter is shut down, dropping trace"),
+            Message::Flush(request) => {
+                let _ = request.reply.send(Err(FlushError::Closed));
+            }
+        }
+    }
+}
+
+#[inline]
+fn with_payload(
+    signal: Signal,
+    json: Vec<u8>,
+    api: &Api,
+    mirror: Option<&Mirror>,
+) -> io::Result<RequestBuilder> {
+    api.inspect_payload(signal, &json);
+
+    let request = signal.request(api, mirror);
+
+    if api.compress {
+        Ok(request
+            .header(CONTENT_ENCODING, "gzip")
+            .body(to_gz(&json, api.compression)?))
+    } else {
+        Ok(request.body(json))
+    }
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline]
+fn to_json_vec<T: Serialize + ?Sized>(data: &T) -> io::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(data)?)
+}
+
+#[cfg(feature = "simd")]
+#[inline]
+fn to_json_vec<T: Serialize + ?Sized>(data: &T) -> io::Result<Vec<u8>> {
+    simd_json::to_vec(data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Gzips a serialized payload
+#[inline]
+pub fn to_gz(json: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    // not presized from the json length, which gzip shrinks by an order of magnitude,
+    // see the `gz_capacity` bench
+    let mut encoder = GzEncoder::new(Vec::new(), compression);
+    encoder.write_all(json)?;
+    encoder.finish()
+}
+
+#[inline]
+pub(crate) fn from_gz(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut json = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut json)?;
+    Ok(json)
+}
+
+// joins serialized items into a json array, skipping the ones adding no element
+fn to_json_array(items: &[io::Result<Vec<u8>>]) -> io::Result<Vec<u8>> {
+    let len = items
+        .iter()
+        .map(|item| item.as_ref().map_or(0, Vec::len) + 1);
+    let mut json = Vec::with_capacity(len.sum::<usize>() + 1);
+    json.push(b'[');
+
+    for item in items {
+        let item = item
+            .as_ref()
+            .map_err(|error| io::Error::new(error.kind(), error.to_string()))?;
+
+        if item.is_empty() {
+            continue;
+        }
+
+        if json.len() > 1 {
+            json.push(b',');
+        }
+        json.extend_from_slice(item);
+    }
+
+    json.push(b']');
+    Ok(json)
+}
+
+// strips the brackets of a serialized json array
+fn array_elements(mut json: Vec<u8>) -> io::Result<Vec<u8>> {
+    if json.len() < 2 || json[0] != b'[' || json[json.len() - 1] != b']' {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "payload is not a json array",
+        ));
+    }
+
+    json.pop();
+    json.remove(0);
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockTransport;
+    use crate::types::{NewrCommon, NewrLog};
+    use reqwest::header::USER_AGENT;
+
+    async fn verify(statuses: &[u16]) -> Result<(), ApiError> {
+        let transport = MockTransport::new(statuses);
+        let result = Api::default()
+            .with_transport(transport.clone())
+            .verify()
+            .await;
+        // stops at the first error
+        assert_eq!(
+            transport.len(),
+            if result.is_ok() { 2 } else { statuses.len() }
+        );
+        result
+    }
+
+    fn logs(len: usize) -> NewrLogs {
+        let mut attributes = NewrAttributes::default();
+        attributes.insert("service.name", "a".repeat(100));
+
+        NewrLogs {
+            logs: (0..len)
+                .map(|_| NewrLog::new(&tracing_core::Level::INFO, UNIX_EPOCH))
+                .collect(),
+            common: NewrCommon { attributes },
+        }
+    }
+
+    #[test]
+    fn chunks_are_sized_on_the_format_sent() {
+        let data = vec![logs(3), logs(3)];
+        let detailed = Api::default().encode_all(&data);
+        let item_len = detailed[0].as_ref().unwrap().len();
+
+        let mut api = Api {
+            max_payload_bytes: 2 * (item_len + 1) + 2,
+            ..Api::default()
+        };
+        assert_eq!(Service::new(&data, &detailed).chunk_len(&api), 2);
+
+        api.log_format = LogFormat::Simple;
+        let simple = api.encode_all(&data);
+        assert_eq!(Service::new(&data, &simple).chunk_len(&api), 1);
+
+        // a flat array of logs, each with the common attributes
+        let json: serde_json::Value =
+            serde_json::from_slice(&to_json_array(&simple).unwrap()).unwrap();
+        let logs = json.as_array().unwrap();
+        assert_eq!(logs.len(), 6);
+        assert!(logs.iter().all(|log| log["service.name"].is_string()));
+    }
+
+    #[test]
+    fn gzip_roundtrips() {
+        let json = to_json_array(&Api::default().encode_all(&[logs(100)])).unwrap();
+
+        let mut fresh = GzEncoder::new(Vec::new(), Compression::default());
+        fresh.write_all(&json).unwrap();
+        let fresh = fresh.finish().unwrap();
+
+        // repeated calls give the same output as a fresh encoder
+        for _ in 0..3 {
+            let gz = to_gz(&json, Compression::default()).unwrap();
+            assert_eq!(gz, fresh);
+            assert_eq!(from_gz(&gz).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn best_compression_is_smaller() {
+        let mut data = logs(200);
+        for (i, log) in data.logs.iter_mut().enumerate() {
+            log.attributes
+                .insert("message", format!("request {i} took {}ms", i * 37 % 101));
+        }
+        let json = to_json_array(&Api::default().encode_all(&[data])).unwrap();
+
+        let best = to_gz(&json, Compression::best()).unwrap();
+        let fast = to_gz(&json, Compression::fast()).unwrap();
+        assert!(best.len() < fast.len());
+        assert_eq!(from_gz(&best).unwrap(), json);
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn simd_json_matches_serde_json() {
+        let mut data = logs(3);
+        for (i, log) in data.logs.iter_mut().enumerate() {
+            log.attributes.insert("message", "quoted \"text\"\n\tüñí €");
+            log.attributes.insert("count", i as u64);
+            log.attributes.insert("delta", -(i as i64));
+            log.attributes.insert("ratio", 0.1 + i as f64);
+            log.attributes.insert("ok", i % 2 == 0);
+            log.attributes
+                .insert("json", serde_json::json!({ "list": [1, null, "x"] }));
+        }
+
+        let simd = to_json_vec(&[&data]).unwrap();
+        let serde = serde_json::to_vec(&[&data]).unwrap();
+
+        // the same json, up to the order of keys
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&simd).unwrap(),
+            serde_json::from_slice::<serde_json::Value>(&serde).unwrap(),
+        );
+    }
+
+    #[test]
+    fn empty_items_add_no_element() {
+        let items = vec![Ok(b"1".to_vec()), Ok(Vec::new()), Ok(b"2".to_vec())];
+        assert_eq!(to_json_array(&items).unwrap(), b"[1,2]");
+        assert_eq!(to_json_array(&[]).unwrap(), b"[]");
+        assert_eq!(array_elements(b"[1,2]".to_vec()).unwrap(), b"1,2");
+        assert!(array_elements(b"{}".to_vec()).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_batch_size_applies_to_both() {
+        let api = Api {
+            log_batch_size: 5,
+            span_batch_size: 50,
+            ..Api::default()
+        };
+        assert_eq!(api.batch_sizes(), (5, 50));
+
+        let api = Api {
+            batch_size: 20,
+            ..api
+        };
+        assert_eq!(api.batch_sizes(), (20, 20));
+    }
+
+    #[test]
+    fn statuses_are_classified() {
+        let retry_after = Some(Duration::from_secs(3));
+        let table = [
+            (200, "None", false),
+            (202, "None", false),
+            (400, "Some(Rejected(400))", false),
+            (401, "Some(Unauthorized(401))", false),
+            (403, "Some(Unauthorized(403))", false),
+            (404, "Some(Rejected(404))", false),
+            (413, "Some(PayloadTooLarge)", false),
+            (429, "Some(RateLimited { retry_after: Some(3s) })", true),
+            (500, "Some(Server(500))", true),
+            (503, "Some(Server(503))", true),
+            (418, "Some(Server(418))", true),
+        ];
+
+        for (status, expected, retryable) in table {
+            let error = ApiError::from_status(status, retry_after);
+            assert_eq!(format!("{:?}", error), expected, "status {}", status);
+            assert_eq!(
+                error.as_ref().is_some_and(ApiError::is_retryable),
+                retryable,
+                "status {}",
+                status
+            );
+            if let Some(error) = error {
+                assert_eq!(error.status(), Some(status));
+            }
+        }
+    }
+
+    #[test]
+    fn retry_delay_saturates() {
+        let api = Api {
+            retry_base: Duration::MAX,
+            retry_max: Duration::MAX,
+            jitter: false,
+            ..Api::default()
+        };
+        assert_eq!(api.retry_delay(u32::MAX), Duration::MAX);
+
+        let api = Api {
+            retry_factor: f64::INFINITY,
+            jitter: false,
+            ..Api::default()
+        };
+        assert_eq!(api.retry_delay(3), api.retry_max);
+
+        let api = Api {
+            retry_factor: f64::NAN,
+            ..Api::default()
+        };
+        assert_eq!(api.retry_delay(3), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn spawn_never_blocks() {
+        let api = Api {
+            overflow_policy: OverflowPolicy::Block,
+            ..Api::default()
+        };
+        assert_eq!(api.spawn().overflow_policy, OverflowPolicy::DropNewest);
+    }
+
+    #[tokio::test]
+    async fn verify_accepts_refused_payloads_only() {
+        assert!(verify(&[]).await.is_ok());
+        assert!(verify(&[400, 413]).await.is_ok());
+        assert!(matches!(
+            verify(&[403]).await,
+            Err(ApiError::Unauthorized(403))
+        ));
+        for status in &[404, 405, 410] {
+            assert!(matches!(
+                verify(&[202, *status]).await,
+                Err(ApiError::Rejected(s)) if s == *status
+            ));
+        }
+    }
+
+    fn rate_limited(retry_after: Duration) -> ApiError {
+        ApiError::RateLimited {
+            retry_after: Some(retry_after),
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped_and_counted() {
+        let api = Api {
+            retry_max: Duration::from_secs(60),
+            max_retries: 2,
+            ..Api::default()
+        };
+        let data: Vec<NewrLogs> = Vec::new();
+        let mut service = Service::new(&data, &[]);
+
+        let status = service.retry(
+            rate_limited(Duration::from_secs(86400)),
+            ApiResponse::default(),
+            &api,
+        );
+        assert!(matches!(status, ServiceStatus::Timeout(d) if d == Duration::from_secs(60)));
+
+        let status = service.retry(
+            rate_limited(Duration::from_secs(5)),
+            ApiResponse::default(),
+            &api,
+        );
+        assert!(matches!(status, ServiceStatus::Timeout(d) if d == Duration::from_secs(5)));
+        assert_eq!(service.retry_count, 2);
+        assert_eq!(api.stats.counters().retries.load(Ordering::Relaxed), 2);
+
+        // a server answering 429 forever still exhausts the retries
+        let status = service.retry(
+            rate_limited(Duration::from_secs(5)),
+            ApiResponse::default(),
+            &api,
+        );
+        assert!(matches!(status, ServiceStatus::Finished));
+        assert!(matches!(
+            service.error,
+            Some(FlushError::RetriesExhausted { status: 429, .. })
+        ));
+    }
+
+    #[test]
+    fn failing_mirror_keeps_primary_circuit_closed() {
+        let mirror: Mirror = (ApiEndpoint::EU, "mirror-key".to_owned());
+        let api = Api {
+            circuit_threshold: Some(1),
+            mirrors: vec![mirror.clone()],
+            ..Api::default()
+        };
+        let data: Vec<NewrLogs> = Vec::new();
+        let mut service = Service::new(&data, &[]);
+        service.mirror = Some(&mirror);
+
+        service.give_up(ApiError::Server(503), ApiResponse::default(), &api);
+
+        assert!(api.target(Signal::Logs, Some(&mirror)).circuit.is_open());
+        assert!(!api.target(Signal::Logs, None).circuit.is_open());
+        assert!(!api.target(Signal::Spans, Some(&mirror)).circuit.is_open());
+    }
+
+    #[test]
+    fn spans_are_sent_to_trace_endpoint() {
+        let api = Api {
+            log_endpoint: ApiEndpoint::EU,
+            trace_endpoint: ApiEndpoint::US,
+            ..Api::default()
+        };
+        assert_eq!(
+            Signal::Spans.url(&api, None),
+            "https://trace-api.newrelic.com/trace/v1"
+        );
+        assert_eq!(
+            Signal::Logs.url(&api, None),
+            "https://log-api.eu.newrelic.com/log/v1"
+        );
+
+        let api = Api {
+            trace_endpoint: ApiEndpoint::Custom("https://nr.example.com".into()),
+            trace_path: Some("/nr/trace/v1".into()),
+            ..Api::default()
+        };
+        assert_eq!(
+            Signal::Spans.url(&api, None),
+            "https://nr.example.com/nr/trace/v1"
+        );
+        assert_eq!(
+            Signal::Logs.url(&api, None),
+            "https://log-api.newrelic.com/log/v1"
+        );
+    }
+
+    #[test]
+    fn fedramp_urls() {
+        let api = Api {
+            log_endpoint: ApiEndpoint::FedRAMP,
+            trace_endpoint: ApiEndpoint::FedRAMP,
+            metric_endpoint: ApiEndpoint::FedRAMP,
+            event_endpoint: ApiEndpoint::FedRAMP,
+            account_id: Some("42".into()),
+            ..Api::default()
+        };
+
+        assert_eq!(
+            Signal::Logs.url(&api, None),
+            "https://gov-log-api.newrelic.com/log/v1"
+        );
+        assert_eq!(
+            Signal::Spans.url(&api, None),
+            "https://gov-trace-api.newrelic.com/trace/v1"
+        );
+        assert_eq!(
+            Signal::Metrics.url(&api, None),
+            "https://gov-metric-api.newrelic.com/metric/v1"
+        );
+        assert_eq!(
+            Signal::Events.url(&api, None),
+            "https://gov-insights-collector.newrelic.com/v1/accounts/42/events"
+        );
+    }
+
+    #[test]
+    fn endpoints_are_parsed() {
+        for region in ["us", "US"] {
+            assert!(matches!(region.parse(), Ok(ApiEndpoint::US)));
+        }
+        for region in ["eu", "Eu"] {
+            assert!(matches!(region.parse(), Ok(ApiEndpoint::EU)));
+        }
+        for region in ["fedramp", "FedRAMP"] {
+            assert!(matches!(region.parse(), Ok(ApiEndpoint::FedRAMP)));
+        }
+        assert!(matches!(
+            "https://collector.example.com/".parse(),
+            Ok(ApiEndpoint::Custom(url)) if url == "https://collector.example.com"
+        ));
+
+        for invalid in ["", "asia", "collector.example.com", "https://"] {
+            let error = invalid.parse::<ApiEndpoint>().err().unwrap();
+            assert!(error.to_string().contains(&format!("`{}`", invalid)));
+        }
+    }
+
+    #[test]
+    fn separate_log_and_trace_endpoints() {
+        let api = Api::from((
+            "key".to_owned(),
+            ApiEndpoint::EU,
+            ApiEndpoint::Custom("https://traces.example.com".into()),
+        ));
+
+        assert_eq!(api.key, "key");
+        assert_eq!(
+            Signal::Logs.url(&api, None),
+            "https://log-api.eu.newrelic.com/log/v1"
+        );
+        assert_eq!(
+            Signal::Spans.url(&api, None),
+            "https://traces.example.com/trace/v1"
+        );
+        assert_eq!(
+            Signal::Metrics.url(&api, None),
+            "https://traces.example.com/metric/v1"
+        );
+    }
+
+    #[test]
+    fn mirrors_and_local_forwarders() {
+        let mirror: Mirror = (ApiEndpoint::FedRAMP, "mirror-key".into());
+        let api = Api {
+            log_endpoint: ApiEndpoint::LocalForwarder(([127, 0, 0, 1], 8080).into()),
+            key: "primary-key".into(),
+            ..Api::default()
+        };
+
+        assert_eq!(Signal::Logs.url(&api, None), "http://127.0.0.1:8080/log/v1");
+        assert_eq!(
+            Signal::Logs.url(&api, Some(&mirror)),
+            "https://gov-log-api.newrelic.com/log/v1"
+        );
+
+        let request = Signal::Logs.request(&api, Some(&mirror)).build().unwrap();
+        assert_eq!(request.headers()["Api-Key"], "mirror-key");
+
+        // a local forwarder may be used without a key
+        let api = Api {
+            key: String::new(),
+            ..api
+        };
+        let request = Signal::Logs.request(&api, None).build().unwrap();
+        assert!(request.headers().get("Api-Key").is_none());
+    }
+
+    #[test]
+    fn key_headers() {
+        let api = Api {
+            key: "license".into(),
+            insert_key: Some("insert".into()),
+            account_id: Some("42".into()),
+            ..Api::default()
+        };
+
+        let request = Signal::Events.request(&api, None).build().unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://insights-collector.newrelic.com/v1/accounts/42/events"
+        );
+        assert_eq!(request.headers()["X-Insert-Key"], "insert");
+
+        let request = Signal::Spans.request(&api, None).build().unwrap();
+        assert_eq!(request.headers()["Api-Key"], "license");
+        assert_eq!(request.headers()["Data-Format"], "newrelic");
+        assert_eq!(request.headers()["Data-Format-Version"], "1");
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    #[test]
+    fn builds_a_rustls_client() {
+        default_client().use_rustls_tls().build().unwrap();
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn builds_a_native_tls_client() {
+        default_client().use_native_tls().build().unwrap();
+    }
+
+    #[test]
+    fn user_agent_of_given_client_is_kept() {
+        let api = Api::default().with_client(Client::new());
+        let request = Signal::Logs.request(&api, None).build().unwrap();
+        assert!(request.headers().get(USER_AGENT).is_none());
+
+        let mut api = api;
+        api.extra_headers
+            .insert(USER_AGENT, "custom".parse().unwrap());
+        let request = Signal::Logs.request(&api, None).build().unwrap();
+        assert_eq!(request.headers()[USER_AGENT], "custom");
+    }
+
+    // fails to serialize, as a buggy `Serialize` impl of a field would
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unencodable"))
+        }
+    }
+
+    impl Sendable for Unencodable {
+        fn signal(_: &[Unencodable]) -> Signal {
+            Signal::Logs
+        }
+
+        fn encode(&self, _: &Api) -> io::Result<Vec<u8>> {
+            to_json_vec(self)
+        }
+
+        fn count(data: &[Unencodable]) -> usize {
+            data.len()
+        }
+
+        fn record_sent(_: &[Unencodable], _: &Counters) {}
+    }
+
+    #[tokio::test]
+    async fn encoding_errors_drop_the_batch() {
+        let transport = MockTransport::new(&[]);
+        let api = Api::default().with_transport(transport.clone());
+
+        let data = [Unencodable, Unencodable];
+        let encoded = api.encode_all(&data);
+        let mut services = api.send_all(&data, &encoded).await;
+
+        let error = api.finish(&mut services, Signal::Logs);
+        assert!(matches!(error, Some(FlushError::Encode { dropped: 2, .. })));
+        assert!(transport.is_empty());
+        assert_eq!(api.stats().batches_failed(), 1);
+    }
 }
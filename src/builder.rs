@@ -0,0 +1,570 @@
+use flate2::Compression;
+use reqwest::{header::HeaderMap, Client};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api::{Api, ApiEndpoint, LogFormat, OverflowPolicy, Signal, Transport};
+use crate::diag;
+use crate::error::{ApiError, BuilderError, FlushError};
+
+/// Builder of a validated [`Api`]
+///
+/// ```rust
+/// use tracing_newrelic::{ApiBuilder, ApiEndpoint};
+///
+/// let api = ApiBuilder::new("YOUR-API-KEY")
+///     .with_endpoint(ApiEndpoint::EU)
+///     .with_batch_size(50)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ApiBuilder {
+    api: Api,
+}
+
+impl ApiBuilder {
+    /// Starts from the defaults of [`Api`] with the given key
+    pub fn new(key: impl Into<String>) -> Self {
+        ApiBuilder {
+            api: Api::from(key.into()),
+        }
+    }
+
+    /// Sets the endpoint of every signal
+    pub fn with_endpoint(mut self, endpoint: ApiEndpoint) -> Self {
+        self.api.log_endpoint = endpoint.clone();
+        self.api.trace_endpoint = endpoint.clone();
+        self.api.metric_endpoint = endpoint.clone();
+        self.api.event_endpoint = endpoint;
+        self
+    }
+
+    /// Sends every signal through a local agent or collector listening on `addr`
+    ///
+    /// Requests go to e.g. `http://127.0.0.1:8080/log/v1`, and the key may be empty
+    /// if the forwarder authenticates on its own, in which case no key header is sent.
+    pub fn with_local_forwarder(self, addr: SocketAddr) -> Self {
+        self.with_endpoint(ApiEndpoint::LocalForwarder(addr))
+    }
+
+    /// Sets the endpoint of logs
+    pub fn with_log_endpoint(mut self, endpoint: ApiEndpoint) -> Self {
+        self.api.log_endpoint = endpoint;
+        self
+    }
+
+    /// Sets the endpoint of spans
+    pub fn with_trace_endpoint(mut self, endpoint: ApiEndpoint) -> Self {
+        self.api.trace_endpoint = endpoint;
+        self
+    }
+
+    /// Sets the endpoint of metrics
+    pub fn with_metric_endpoint(mut self, endpoint: ApiEndpoint) -> Self {
+        self.api.metric_endpoint = endpoint;
+        self
+    }
+
+    /// Sets the endpoint of custom events
+    pub fn with_event_endpoint(mut self, endpoint: ApiEndpoint) -> Self {
+        self.api.event_endpoint = endpoint;
+        self
+    }
+
+    /// Sets the path of the Log Api on custom endpoints, e.g. `/nr/log/v1`
+    pub fn with_log_path(mut self, path: impl Into<String>) -> Self {
+        self.api.log_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path of the Trace Api on custom endpoints
+    pub fn with_trace_path(mut self, path: impl Into<String>) -> Self {
+        self.api.trace_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path of the Metric Api on custom endpoints
+    pub fn with_metric_path(mut self, path: impl Into<String>) -> Self {
+        self.api.metric_path = Some(path.into());
+        self
+    }
+
+    /// Also sends every batch to `endpoint` with `key`
+    pub fn with_mirror(mut self, endpoint: ApiEndpoint, key: impl Into<String>) -> Self {
+        self.api.mirrors.push((endpoint, key.into()));
+        self
+    }
+
+    /// Sets the key used by the Trace Api
+    pub fn with_trace_key(mut self, key: impl Into<String>) -> Self {
+        self.api.trace_key = Some(key.into());
+        self
+    }
+
+    /// Sets the Insights Insert Key used by the Event Api
+    pub fn with_insert_key(mut self, key: impl Into<String>) -> Self {
+        self.api.insert_key = Some(key.into());
+        self
+    }
+
+    /// Sets the account id, enabling custom events
+    pub fn with_account_id(mut self, account_id: impl Into<String>) -> Self {
+        self.api.account_id = Some(account_id.into());
+        self
+    }
+
+    /// Uses a pre-configured http client
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.api.client = client;
+        self
+    }
+
+    /// Sends requests through `transport` instead of the http client
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.api = self.api.with_transport(transport);
+        self
+    }
+
+    /// Sets the headers added to every request
+    pub fn with_extra_headers(mut self, headers: HeaderMap) -> Self {
+        self.api.extra_headers = headers;
+        self
+    }
+
+    /// Sets the timeout of a single request
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.api.request_timeout = timeout;
+        self
+    }
+
+    /// Sets the batch size of both logs and spans
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.api = self.api.with_batch_size(batch_size);
+        self
+    }
+
+    /// Sets the number of queued logs triggering a flush
+    pub fn with_log_batch_size(mut self, batch_size: usize) -> Self {
+        self.api.log_batch_size = batch_size;
+        self
+    }
+
+    /// Sets the number of queued spans triggering a flush
+    pub fn with_span_batch_size(mut self, batch_size: usize) -> Self {
+        self.api.span_batch_size = batch_size;
+        self
+    }
+
+    /// Sets the maximum number of traces waiting to be sent
+    pub fn with_max_queue_len(mut self, max_queue_len: usize) -> Self {
+        self.api.max_queue_len = max_queue_len;
+        self
+    }
+
+    /// Sets what to do with traces once the queue is full
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.api.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Sets the shape of the payloads sent to the Log Api
+    pub fn with_log_format(mut self, log_format: LogFormat) -> Self {
+        self.api.log_format = log_format;
+        self
+    }
+
+    /// Sets the maximum size of the serialized payload of a single request
+    pub fn with_max_payload_bytes(mut self, max_payload_bytes: usize) -> Self {
+        self.api.max_payload_bytes = max_payload_bytes;
+        self
+    }
+
+    /// Sets the maximum time data stays buffered before being flushed
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.api.flush_interval = flush_interval;
+        self
+    }
+
+    /// Sets the maximum number of requests in flight at once per signal
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.api.max_concurrency = max_concurrency;
+        self
+    }
+
+    /// Sets the maximum number of requests per second to each destination of each signal
+    pub fn with_max_requests_per_second(mut self, max_requests_per_second: f64) -> Self {
+        self.api.max_requests_per_second = Some(max_requests_per_second);
+        self
+    }
+
+    /// Sets the maximum number of requests in flight at once across all signals and mirrors
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.api.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Adapts the number of items per request to backpressure, between `min` and `max`
+    pub fn with_adaptive_batch(mut self, min: usize, max: usize) -> Self {
+        self.api.adaptive_batch = Some((min, max));
+        self
+    }
+
+    /// Enables or disables sending logs
+    pub fn with_logs_enabled(mut self, logs_enabled: bool) -> Self {
+        self.api.logs_enabled = logs_enabled;
+        self
+    }
+
+    /// Enables or disables sending spans
+    pub fn with_traces_enabled(mut self, traces_enabled: bool) -> Self {
+        self.api.traces_enabled = traces_enabled;
+        self
+    }
+
+    /// Enables or disables sending metrics
+    pub fn with_metrics_enabled(mut self, metrics_enabled: bool) -> Self {
+        self.api.metrics_enabled = metrics_enabled;
+        self
+    }
+
+    /// Logs payloads instead of sending them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.api.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the gzip compression level, `None` disables compression
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.api.compress = compression.is_some();
+        self.api.compression = compression.unwrap_or_default();
+        self
+    }
+
+    /// Sets the backoff between retries, from `base` up to `max`
+    pub fn with_retry_delay(mut self, base: Duration, max: Duration) -> Self {
+        self.api.retry_base = base;
+        self.api.retry_max = max;
+        self
+    }
+
+    /// Sets the multiplier of the delay after each retry
+    pub fn with_retry_factor(mut self, factor: f64) -> Self {
+        self.api.retry_factor = factor;
+        self
+    }
+
+    /// Randomizes each delay between retries by ±50%
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.api.jitter = jitter;
+        self
+    }
+
+    /// Sets the maximum number of retries before a batch is dropped
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.api.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the maximum time spent retrying before a batch is dropped
+    pub fn with_max_retry_elapsed(mut self, max_retry_elapsed: Duration) -> Self {
+        self.api.max_retry_elapsed = Some(max_retry_elapsed);
+        self
+    }
+
+    /// Overrides which errors are retried
+    pub fn with_retry_if(
+        mut self,
+        retry_if: impl Fn(&ApiError) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.api.retry_if = Some(Arc::new(retry_if));
+        self
+    }
+
+    /// Opens the circuit breaker for `cooldown` after `threshold` consecutive failed batches
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.api.circuit_threshold = Some(threshold);
+        self.api.circuit_cooldown = cooldown;
+        self
+    }
+
+    /// Sets the callback invoked whenever a batch is dropped or accepted with warnings
+    pub fn with_on_error(mut self, on_error: impl Fn(&FlushError) + Send + Sync + 'static) -> Self {
+        self.api.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    /// Sets the callback invoked with every uncompressed payload before it's sent
+    pub fn with_on_payload(
+        mut self,
+        on_payload: impl Fn(Signal, &[u8]) + Send + Sync + 'static,
+    ) -> Self {
+        self.api.on_payload = Some(Arc::new(on_payload));
+        self
+    }
+
+    /// Sets the callback invoked with the `requestId` of every accepted batch
+    pub fn with_on_sent(
+        mut self,
+        on_sent: impl Fn(Signal, Option<&str>) + Send + Sync + 'static,
+    ) -> Self {
+        self.api.on_sent = Some(Arc::new(on_sent));
+        self
+    }
+
+    /// Sets the `service.name` of logs and spans
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.api.service_name = Some(service_name.into());
+        self
+    }
+
+    /// Sets the `host.name` of logs and spans
+    pub fn with_host_name(mut self, host_name: impl Into<String>) -> Self {
+        self.api.host_name = Some(host_name.into());
+        self
+    }
+
+    /// Adds an attribute shared by every log
+    pub fn with_common_attribute(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.api.common_attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds an attribute describing the entity to logs and spans
+    pub fn with_resource_attribute(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.api
+            .resource_attributes
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the `entity.guid` of logs and spans
+    pub fn with_entity_guid(self, entity_guid: impl Into<String>) -> Self {
+        self.with_resource_attribute("entity.guid", entity_guid.into())
+    }
+
+    /// Sets the `instrumentation.provider` of logs and spans
+    pub fn with_instrumentation_provider(self, provider: impl Into<String>) -> Self {
+        self.with_resource_attribute("instrumentation.provider", provider.into())
+    }
+
+    /// Sets the directory for persisting batches that exhausted their retries
+    pub fn with_dead_letter_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.api.dead_letter_dir = Some(dir.into());
+        self
+    }
+
+    /// Reports numeric span attributes as gauge metrics
+    pub fn with_span_metrics(mut self, span_metrics: bool) -> Self {
+        self.api.span_metrics = span_metrics;
+        self
+    }
+
+    /// Merges the spans of a trace into one payload entry, so they are sent together
+    pub fn with_coalesce_traces(mut self, coalesce_traces: bool) -> Self {
+        self.api.coalesce_traces = coalesce_traces;
+        self
+    }
+
+    /// Reports the durations of spans as `span.duration` summary metrics, by name
+    pub fn with_span_duration_metrics(mut self, span_duration_metrics: bool) -> Self {
+        self.api.span_duration_metrics = span_duration_metrics;
+        self
+    }
+
+    /// Sets the prefixes of numeric event fields reported as `count` metrics
+    ///
+    /// Defaults to `monotonic_counter.` and `counter.`, e.g. `info!(counter.requests = 1)`
+    /// increments the `requests` count, an empty list disables it.
+    pub fn with_counter_prefixes<I, P>(mut self, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.api.counter_prefixes = prefixes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Validates the configuration
+    ///
+    /// Whitespace around keys is trimmed, and a warning is logged if the key doesn't look
+    /// like a New Relic key while no custom endpoint is used.
+    pub fn build(self) -> Result<Api, BuilderError> {
+        let mut api = self.api;
+
+        // e.g. a trailing newline read from a secret file
+        let mut keys = vec![&mut api.key];
+        keys.extend(api.trace_key.as_mut());
+        keys.extend(api.insert_key.as_mut());
+        keys.extend(api.mirrors.iter_mut().map(|(_, key)| key));
+
+        for key in keys {
+            *key = key.trim().to_owned();
+        }
+
+        let primary = [
+            &api.log_endpoint,
+            &api.trace_endpoint,
+            &api.metric_endpoint,
+            &api.event_endpoint,
+        ];
+
+        // local forwarders may authenticate on their own, any other destination needs a key
+        let signals = [Signal::Logs, Signal::Spans, Signal::Metrics, Signal::Events];
+        let mirrors = api.mirrors.iter().map(Some);
+
+        for mirror in std::iter::once(None).chain(mirrors) {
+            for signal in &signals {
+                let (endpoint, key) = signal.endpoint(&api, mirror);
+
+                if key.is_empty() && !matches!(endpoint, ApiEndpoint::LocalForwarder(_)) {
+                    return Err(BuilderError::EmptyKey);
+                }
+            }
+        }
+
+        let is_custom = primary.iter().any(|endpoint| {
+            matches!(
+                endpoint,
+                ApiEndpoint::Custom(_) | ApiEndpoint::LocalForwarder(_)
+            )
+        });
+
+        if !is_custom && !looks_like_key(&api.key) {
+            diag::warn!("api key doesn't look like a New Relic license, user or insert key");
+        }
+
+        for path in [&mut api.log_path, &mut api.trace_path, &mut api.metric_path]
+            .iter_mut()
+            .filter_map(|path| path.as_mut())
+        {
+            if !path.starts_with('/') {
+                path.insert(0, '/');
+            }
+        }
+
+        if api.log_batch_size == 0 || api.span_batch_size == 0 {
+            return Err(BuilderError::ZeroBatchSize);
+        }
+
+        if api.max_queue_len == 0 {
+            return Err(BuilderError::ZeroQueueLen);
+        }
+
+        let mut endpoints = vec![
+            &mut api.log_endpoint,
+            &mut api.trace_endpoint,
+            &mut api.metric_endpoint,
+            &mut api.event_endpoint,
+        ];
+        endpoints.extend(api.mirrors.iter_mut().map(|(endpoint, _)| endpoint));
+
+        for endpoint in endpoints {
+            if let ApiEndpoint::Custom(url) = endpoint {
+                *endpoint = ApiEndpoint::try_from(url.as_str())?;
+            }
+        }
+
+        Ok(api)
+    }
+}
+
+// license keys have 40 characters, user and insert keys a prefix
+// https://docs.newrelic.com/docs/apis/intro-apis/new-relic-api-keys/
+fn looks_like_key(key: &str) -> bool {
+    key.starts_with("NRAK-")
+        || key.starts_with("NRII-")
+        || (key.len() == 40 && key.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_sizes() {
+        let builder = || ApiBuilder::new("NRAK-KEY");
+
+        assert!(builder().build().is_ok());
+        assert!(matches!(
+            builder().with_span_batch_size(0).build(),
+            Err(BuilderError::ZeroBatchSize)
+        ));
+        assert!(matches!(
+            builder().with_max_queue_len(0).build(),
+            Err(BuilderError::ZeroQueueLen)
+        ));
+    }
+
+    #[test]
+    fn empty_key_only_for_local_destinations() {
+        let local = || ApiBuilder::new(" ").with_local_forwarder(([127, 0, 0, 1], 8080).into());
+
+        assert!(local().build().is_ok());
+        assert!(matches!(
+            local().with_mirror(ApiEndpoint::EU, "").build(),
+            Err(BuilderError::EmptyKey)
+        ));
+        assert!(matches!(
+            local().with_trace_endpoint(ApiEndpoint::US).build(),
+            Err(BuilderError::EmptyKey)
+        ));
+        assert!(local()
+            .with_mirror(ApiEndpoint::EU, "NRAK-KEY")
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn trims_keys_and_paths() {
+        let api = ApiBuilder::new(" NRAK-KEY\n")
+            .with_trace_key("NRAK-TRACE ")
+            .with_mirror(ApiEndpoint::EU, "\tNRAK-MIRROR")
+            .with_log_path("logs")
+            .build()
+            .unwrap();
+
+        assert_eq!(api.key, "NRAK-KEY");
+        assert_eq!(api.trace_key.as_deref(), Some("NRAK-TRACE"));
+        assert_eq!(api.mirrors[0].1, "NRAK-MIRROR");
+        assert_eq!(api.log_path.as_deref(), Some("/logs"));
+    }
+
+    #[test]
+    fn validates_custom_endpoints() {
+        let api = ApiBuilder::new("NRAK-KEY")
+            .with_log_endpoint(ApiEndpoint::Custom("https://logs.example.com//".into()))
+            .with_mirror(
+                ApiEndpoint::Custom("http://mirror.example.com/".into()),
+                "NRAK-KEY",
+            )
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            api.log_endpoint,
+            ApiEndpoint::Custom(ref url) if url == "https://logs.example.com"
+        ));
+        assert!(matches!(
+            api.mirrors[0].0,
+            ApiEndpoint::Custom(ref url) if url == "http://mirror.example.com"
+        ));
+
+        assert!(matches!(
+            ApiBuilder::new("NRAK-KEY")
+                .with_trace_endpoint(ApiEndpoint::Custom("traces.example.com".into()))
+                .build(),
+            Err(BuilderError::InvalidEndpoint(_))
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Source of the timestamps and durations of spans and logs
+pub trait Clock: Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> SystemTime;
+}
+
+/// The wall clock, used by default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, e.g. for deterministic tests
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<SystemTime>>);
+
+impl ManualClock {
+    /// Starts the clock at `now`
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock(Arc::new(Mutex::new(now)))
+    }
+
+    /// Sets the current time
+    pub fn set(&self, now: SystemTime) {
+        *self.time() = now;
+    }
+
+    /// Moves the current time forward
+    pub fn advance(&self, duration: Duration) {
+        *self.time() += duration;
+    }
+
+    // a plain time can't be left inconsistent, so a panic elsewhere doesn't stop the clock
+    fn time(&self) -> MutexGuard<'_, SystemTime> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.time()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn manual_clock_survives_poisoning() {
+        let clock = ManualClock::new(UNIX_EPOCH);
+
+        let poisoned = clock.clone();
+        let _ = std::thread::spawn(move || {
+            let _time = poisoned.0.lock().unwrap();
+            panic!("poisoning the clock");
+        })
+        .join();
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn system_clock_is_real() {
+        assert!(SystemClock.now() > UNIX_EPOCH);
+    }
+}
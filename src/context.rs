@@ -0,0 +1,161 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::ParseTraceParentError;
+
+/// A parsed W3C `traceparent` header
+///
+/// Recording it as the `traceparent` field of a root span makes the trace join the upstream one:
+///
+/// ```rust
+/// let span = tracing::info_span!(
+///     "request",
+///     traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+/// );
+/// ```
+///
+/// <https://www.w3.org/TR/trace-context/#traceparent-header>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    /// Id of the whole trace, 32 lowercase hex digits
+    pub trace_id: String,
+    /// Id of the upstream span, 16 lowercase hex digits
+    pub parent_id: String,
+    /// Whether the upstream service sampled the trace
+    pub sampled: bool,
+}
+
+impl FromStr for TraceParent {
+    type Err = ParseTraceParentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason| ParseTraceParentError::new(s, reason);
+
+        let parts: Vec<&str> = s.trim().split('-').collect();
+
+        let (version, trace_id, parent_id, flags) = match parts.as_slice() {
+            [version, trace_id, parent_id, flags, rest @ ..] => {
+                // later versions may append fields
+                if *version == "00" && !rest.is_empty() {
+                    return Err(err("unexpected fields for version 00"));
+                }
+
+                (*version, *trace_id, *parent_id, *flags)
+            }
+            _ => return Err(err("expected four fields")),
+        };
+
+        if !is_hex(version, 2) || version == "ff" {
+            return Err(err("invalid version"));
+        }
+
+        if !is_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return Err(err("invalid trace id"));
+        }
+
+        if !is_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+            return Err(err("invalid parent id"));
+        }
+
+        if !is_hex(flags, 2) {
+            return Err(err("invalid trace flags"));
+        }
+
+        let flags = u8::from_str_radix(flags, 16).map_err(|_| err("invalid trace flags"))?;
+
+        Ok(TraceParent {
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            sampled: flags & 0x01 != 0,
+        })
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            self.trace_id,
+            self.parent_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    const PARENT_ID: &str = "b7ad6b7169203331";
+
+    fn parse(s: &str) -> Result<TraceParent, ParseTraceParentError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_and_displays() {
+        let header = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        let parent = parse(&header).unwrap();
+
+        assert_eq!(parent.trace_id, TRACE_ID);
+        assert_eq!(parent.parent_id, PARENT_ID);
+        assert!(parent.sampled);
+        assert_eq!(parent.to_string(), header);
+
+        let parent = parse(&format!(" 00-{}-{}-00\n", TRACE_ID, PARENT_ID)).unwrap();
+        assert!(!parent.sampled);
+        assert_eq!(
+            parent.to_string(),
+            format!("00-{}-{}-00", TRACE_ID, PARENT_ID)
+        );
+    }
+
+    #[test]
+    fn reads_the_sampled_flag_only() {
+        let parent = parse(&format!("00-{}-{}-03", TRACE_ID, PARENT_ID)).unwrap();
+        assert!(parent.sampled);
+
+        // other flags aren't kept
+        assert!(parent.to_string().ends_with("-01"));
+
+        let parent = parse(&format!("00-{}-{}-02", TRACE_ID, PARENT_ID)).unwrap();
+        assert!(!parent.sampled);
+    }
+
+    #[test]
+    fn accepts_fields_of_later_versions() {
+        let parent = parse(&format!("01-{}-{}-01-later", TRACE_ID, PARENT_ID)).unwrap();
+        assert_eq!(parent.trace_id, TRACE_ID);
+
+        // rewritten in the only version known
+        assert!(parent.to_string().starts_with("00-"));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let zeros = "0".repeat(32);
+        let invalid = [
+            String::new(),
+            format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID),
+            format!("ff-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("0-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", zeros, PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, &zeros[..16]),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), PARENT_ID),
+            format!("00-{}-{}-01", &TRACE_ID[..30], PARENT_ID),
+            format!("00-{}-{}-1", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-0g", TRACE_ID, PARENT_ID),
+        ];
+
+        for header in &invalid {
+            assert!(parse(header).is_err(), "{:?} parsed", header);
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Internal diagnostics of the exporter, emitted through `log` by default
+//! or through `tracing` with the `internal-tracing` feature
+//!
+//! Either way, their target starts with `tracing_newrelic`, which the layer never exports.
+
+#[cfg(not(feature = "internal-tracing"))]
+pub(crate) use log::{debug, error, info, warn};
+
+#[cfg(feature = "internal-tracing")]
+pub(crate) use tracing::{debug, error, info, warn};
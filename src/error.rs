@@ -0,0 +1,320 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Error returned when a batch of logs or spans couldn't be delivered
+#[derive(Debug)]
+pub enum FlushError {
+    /// Request couldn't be sent, even after retrying
+    Network {
+        /// Last error returned by the http client
+        error: reqwest::Error,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// Payload couldn't be serialized or compressed
+    Encode {
+        /// Serialization or compression error
+        error: io::Error,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// Dead letters couldn't be read or deleted
+    DeadLetter(io::Error),
+    /// New Relic rejected the payload with a non-retryable status
+    Rejected {
+        /// Response status code
+        status: u16,
+        /// Errors reported in the response body
+        errors: Vec<String>,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// New Relic refused the Api key, it's either invalid or revoked
+    Unauthorized {
+        /// Response status code, either 401 or 403
+        status: u16,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// New Relic kept responding with a retryable status
+    RetriesExhausted {
+        /// Last response status code
+        status: u16,
+        /// Number of retries performed
+        retries: u32,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// Queued data wasn't delivered before the deadline of the flush
+    Timeout {
+        /// Time given to the flush
+        deadline: Duration,
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// Payload wasn't sent because the circuit breaker is open
+    CircuitOpen {
+        /// Number of items dropped
+        dropped: usize,
+    },
+    /// The exporter stopped before handling the request
+    Closed,
+    /// New Relic accepted the payload, but reported warnings about it
+    Warnings {
+        /// Id of the request, for support tickets
+        request_id: Option<String>,
+        /// Warnings reported in the response body
+        warnings: Vec<String>,
+    },
+}
+
+/// Why a single request to New Relic didn't succeed
+#[derive(Debug)]
+pub enum ApiError {
+    /// Request couldn't be sent or timed out
+    Network(reqwest::Error),
+    /// The request rate quota has been exceeded
+    RateLimited {
+        /// Delay requested by the `retry-after` header, if present
+        retry_after: Option<Duration>,
+    },
+    /// The Api key is invalid or revoked, 401 or 403
+    Unauthorized(u16),
+    /// The payload was too big
+    PayloadTooLarge,
+    /// The payload was rejected with another 4xx status
+    Rejected(u16),
+    /// Any other status, usually 5xx
+    Server(u16),
+    /// Payload couldn't be serialized or compressed
+    Serialization(io::Error),
+}
+
+impl ApiError {
+    /// Classifies a response status, `None` on success
+    pub fn from_status(status: u16, retry_after: Option<Duration>) -> Option<ApiError> {
+        match status {
+            200..=299 => None,
+            401 | 403 => Some(ApiError::Unauthorized(status)),
+            413 => Some(ApiError::PayloadTooLarge),
+            429 => Some(ApiError::RateLimited { retry_after }),
+            400 | 404 | 405 | 409 | 410 | 411 => Some(ApiError::Rejected(status)),
+            _ => Some(ApiError::Server(status)),
+        }
+    }
+
+    /// Response status code, if a response was received
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Network(error) => error.status().map(|status| status.as_u16()),
+            ApiError::RateLimited { .. } => Some(429),
+            ApiError::PayloadTooLarge => Some(413),
+            ApiError::Unauthorized(status)
+            | ApiError::Rejected(status)
+            | ApiError::Server(status) => Some(*status),
+            ApiError::Serialization(_) => None,
+        }
+    }
+
+    /// Whether the request is retried by default
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Network(_) | ApiError::RateLimited { .. } | ApiError::Server(_)
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Network(error) => write!(f, "request failed with: {error}"),
+            ApiError::Serialization(error) => write!(f, "failed to encode payload: {error}"),
+            error => match error.status() {
+                Some(status) => write!(f, "recevied {status} response"),
+                None => write!(f, "recevied <none> response"),
+            },
+        }
+    }
+}
+
+impl Error for ApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ApiError::Network(error) => Some(error),
+            ApiError::Serialization(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl FlushError {
+    /// Number of items dropped because of this error
+    pub fn dropped(&self) -> usize {
+        match self {
+            FlushError::Network { dropped, .. }
+            | FlushError::Encode { dropped, .. }
+            | FlushError::Rejected { dropped, .. }
+            | FlushError::Unauthorized { dropped, .. }
+            | FlushError::CircuitOpen { dropped }
+            | FlushError::Timeout { dropped, .. }
+            | FlushError::RetriesExhausted { dropped, .. } => *dropped,
+            FlushError::DeadLetter(_) | FlushError::Closed | FlushError::Warnings { .. } => 0,
+        }
+    }
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushError::Network { error, dropped } => {
+                write!(f, "request failed with: {error}, dropped={dropped}")
+            }
+            FlushError::Encode { error, dropped } => {
+                write!(f, "failed to encode payload: {error}, dropped={dropped}")
+            }
+            FlushError::DeadLetter(error) => write!(f, "failed to access dead letters: {error}"),
+            FlushError::Rejected {
+                status,
+                errors,
+                dropped,
+            } => {
+                write!(f, "recevied {status} response, dropped={dropped}")?;
+                if !errors.is_empty() {
+                    write!(f, ", errors={errors:?}")?;
+                }
+                Ok(())
+            }
+            FlushError::Unauthorized { status, dropped } => write!(
+                f,
+                "recevied {status} response, the api key is invalid or revoked, dropped={dropped}"
+            ),
+            FlushError::RetriesExhausted {
+                status,
+                retries,
+                dropped,
+            } => write!(
+                f,
+                "recevied {status} response after {retries} retries, dropped={dropped}"
+            ),
+            FlushError::Timeout { deadline, dropped } => {
+                write!(f, "timed out after {deadline:?}, dropped={dropped}")
+            }
+            FlushError::CircuitOpen { dropped } => {
+                write!(f, "circuit breaker is open, dropped={dropped}")
+            }
+            FlushError::Closed => write!(f, "exporter is closed"),
+            FlushError::Warnings {
+                request_id,
+                warnings,
+            } => write!(
+                f,
+                "payload accepted with warnings: {warnings:?}, request_id={}",
+                request_id.as_deref().unwrap_or("<none>")
+            ),
+        }
+    }
+}
+
+impl Error for FlushError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlushError::Network { error, .. } => Some(error),
+            FlushError::Encode { error, .. } | FlushError::DeadLetter(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned when parsing an invalid [`ApiEndpoint`](crate::ApiEndpoint)
+#[derive(Debug)]
+pub struct ParseEndpointError {
+    endpoint: String,
+    reason: &'static str,
+}
+
+impl ParseEndpointError {
+    pub(crate) fn new(endpoint: &str, reason: &'static str) -> Self {
+        ParseEndpointError {
+            endpoint: endpoint.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseEndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid endpoint `{}`: {}", self.endpoint, self.reason)
+    }
+}
+
+impl Error for ParseEndpointError {}
+
+/// Error returned when parsing an invalid [`TraceParent`](crate::TraceParent)
+#[derive(Debug)]
+pub struct ParseTraceParentError {
+    traceparent: String,
+    reason: &'static str,
+}
+
+impl ParseTraceParentError {
+    pub(crate) fn new(traceparent: &str, reason: &'static str) -> Self {
+        ParseTraceParentError {
+            traceparent: traceparent.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseTraceParentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid traceparent `{}`: {}",
+            self.traceparent, self.reason
+        )
+    }
+}
+
+impl Error for ParseTraceParentError {}
+
+/// Error returned by [`ApiBuilder::build`](crate::ApiBuilder::build)
+#[derive(Debug)]
+pub enum BuilderError {
+    /// The key of a destination other than a local forwarder is empty
+    EmptyKey,
+    /// A batch size is zero
+    ZeroBatchSize,
+    /// The maximum queue length is zero, which would drop every trace
+    ZeroQueueLen,
+    /// A custom endpoint isn't a valid url
+    InvalidEndpoint(ParseEndpointError),
+}
+
+impl From<ParseEndpointError> for BuilderError {
+    fn from(error: ParseEndpointError) -> Self {
+        BuilderError::InvalidEndpoint(error)
+    }
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuilderError::EmptyKey => write!(f, "api key is empty"),
+            BuilderError::ZeroBatchSize => write!(f, "batch size must be greater than zero"),
+            BuilderError::ZeroQueueLen => write!(f, "max queue length must be greater than zero"),
+            BuilderError::InvalidEndpoint(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for BuilderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuilderError::InvalidEndpoint(error) => Some(error),
+            _ => None,
+        }
+    }
+}
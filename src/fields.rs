@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Debug;
+use tracing_core::field::{Field, Visit};
+
+use crate::types::{NewrAttributes, Value};
+
+const TRUNCATED: &str = "…[truncated]";
+const DROPPED: &str = "nr.attributes.dropped";
+
+/// Options applied when recording `tracing` fields as attributes
+pub struct FieldConfig {
+    /// Keys whose values are redacted, matched case-insensitively
+    pub redact_keys: Vec<String>,
+    /// Maximum length in bytes of string values
+    pub max_attribute_len: usize,
+    /// Maximum number of attributes of a span or log
+    pub max_attributes: usize,
+    /// Whether to drop null, empty string and empty array values
+    pub drop_empty: bool,
+    /// Keys of the only fields recorded, if set
+    pub allowlist: Option<HashSet<String>>,
+    /// Keys of fields never recorded, even if allowed
+    pub denylist: HashSet<String>,
+    /// New keys of fields, keyed by their name in code
+    pub renames: HashMap<String, String>,
+    /// Name in code of the field holding the error of a span or event
+    pub error_key: String,
+}
+
+impl Default for FieldConfig {
+    fn default() -> Self {
+        FieldConfig {
+            redact_keys: Vec::new(),
+            // https://docs.newrelic.com/docs/data-apis/manage-data/view-system-limits/
+            max_attribute_len: 4096,
+            max_attributes: 254,
+            drop_empty: false,
+            allowlist: None,
+            denylist: HashSet::new(),
+            renames: HashMap::new(),
+            error_key: "error".into(),
+        }
+    }
+}
+
+impl FieldConfig {
+    pub fn visitor<'a>(&'a self, attributes: &'a mut NewrAttributes) -> FieldVisitor<'a> {
+        FieldVisitor {
+            attributes,
+            config: self,
+        }
+    }
+
+    fn truncate(&self, mut value: String) -> String {
+        if value.len() <= self.max_attribute_len {
+            return value;
+        }
+
+        // the marker alone wouldn't fit, cut without it
+        let marker = if self.max_attribute_len < TRUNCATED.len() {
+            ""
+        } else {
+            TRUNCATED
+        };
+
+        let mut len = self.max_attribute_len - marker.len();
+
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        value.truncate(len);
+        value.push_str(marker);
+        value
+    }
+
+    fn is_exported(&self, key: &str) -> bool {
+        !self.denylist.contains(key)
+            && self
+                .allowlist
+                .as_ref()
+                .is_none_or(|allowlist| allowlist.contains(key))
+    }
+
+    fn rename<'k>(&'k self, key: &'k str) -> &'k str {
+        self.renames.get(key).map_or(key, String::as_str)
+    }
+
+    /// Key the error field is recorded under, once renamed
+    pub fn error_attribute(&self) -> &str {
+        self.rename(&self.error_key)
+    }
+
+    fn is_redacted(&self, key: &str) -> bool {
+        self.redact_keys
+            .iter()
+            .any(|redacted| redacted.eq_ignore_ascii_case(key))
+    }
+}
+
+/// A [`Visit`] recording fields into attributes according to a [`FieldConfig`]
+pub struct FieldVisitor<'a> {
+    attributes: &'a mut NewrAttributes,
+    config: &'a FieldConfig,
+}
+
+impl FieldVisitor<'_> {
+    fn record<V: Into<Value>>(&mut self, source: &str, value: V) {
+        // lists apply to the name in code, redaction to both names
+        if !self.config.is_exported(source) {
+            return;
+        }
+
+        // when several fields map to the same key, the last recorded wins
+        let key = self.config.rename(source);
+
+        let value = value.into();
+        let attributes = &mut self.attributes.0;
+
+        if self.config.drop_empty && value.is_empty() {
+            // also forgets a value recorded earlier
+            attributes.remove(key);
+            return;
+        }
+
+        // the dropped count doesn't count towards the limit
+        let len = attributes.len() - attributes.contains_key(DROPPED) as usize;
+
+        if !attributes.contains_key(key) && len >= self.config.max_attributes {
+            let dropped = match attributes.get(DROPPED) {
+                Some(Value::U64(dropped)) => *dropped,
+                _ => 0,
+            };
+            self.attributes.insert(DROPPED, dropped + 1);
+            return;
+        }
+
+        if self.config.is_redacted(source) || self.config.is_redacted(key) {
+            self.attributes.insert(key, "[REDACTED]");
+        } else {
+            self.attributes.insert(key, value);
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field.name(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field.name(), value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field.name(), value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field.name(), value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = self.config.truncate(value.to_string());
+        self.record(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let value = self.config.truncate(format!("{:?}", value));
+        self.record(field.name(), value);
+    }
+
+    // e.g. `error = &err as &dyn Error`
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        let message = self.config.truncate(value.to_string());
+        self.record(field.name(), message);
+
+        if field.name() != self.config.error_key {
+            return;
+        }
+
+        // the concrete type is erased, so no `error.class` is derived
+        let mut chain = Vec::new();
+        let mut source = value.source();
+
+        while let Some(error) = source {
+            chain.push(serde_json::Value::from(
+                self.config.truncate(error.to_string()),
+            ));
+            source = error.source();
+        }
+
+        if !chain.is_empty() {
+            self.record("error.chain", serde_json::Value::from(chain));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use flume::WeakSender;
+use std::time::{Duration, Instant};
+
+use crate::error::FlushError;
+use crate::layer::{FlushRequest, Message};
+use crate::stats::FlushStats;
+
+/// A guard that flushes buffered data when dropped
+///
+/// Data are buffered until the batch size or `flush_interval` is reached, and the layer is
+/// usually never dropped when installed as the global default subscriber. Holding this
+/// guard until the end of `main` makes sure nothing is lost on exit.
+///
+/// Dropping the guard blocks the current thread until the flush completes. For a layer
+/// created with [`layer`](crate::layer), the flush is done by the reporting thread, so it
+/// doesn't require any async runtime.
+///
+/// For a layer of [`ApiHandle::layer`](crate::ApiHandle::layer), the flush runs on the
+/// runtime of [`Api::spawn`](crate::Api::spawn). Blocking one of its threads deadlocks a
+/// `current_thread` runtime, so drop the guard outside of it, or await
+/// [`ApiHandle::flush`](crate::ApiHandle::flush) instead.
+///
+/// ```rust
+/// use tracing_subscriber::{layer::SubscriberExt, Registry};
+///
+/// let layer = tracing_newrelic::layer("YOUR-API-KEY");
+/// let guard = layer.flush_guard();
+///
+/// tracing::subscriber::set_global_default(Registry::default().with(layer)).unwrap();
+///
+/// // ... run the application
+///
+/// // sends buffered data before the process exits
+/// if let Err(error) = guard.flush() {
+///     eprintln!("failed to flush: {error}");
+/// }
+/// ```
+#[must_use = "buffered data are flushed when the guard is dropped"]
+pub struct FlushGuard {
+    // doesn't keep the reporting thread alive once the layer is dropped
+    pub(crate) channel: WeakSender<Message>,
+}
+
+impl FlushGuard {
+    /// Sends buffered data, blocking until done
+    ///
+    /// Returns once every trace whose root span closed before the call was delivered, or
+    /// dropped after exhausting its retries. Spans still open are not included.
+    ///
+    /// Returns the first error of this flush, see [`ApiHandle::flush`](crate::ApiHandle::flush).
+    pub fn flush(&self) -> Result<(), FlushError> {
+        self.drain().map(drop)
+    }
+
+    /// Sends buffered data like [`Self::flush`], returning how much of it was delivered
+    pub fn drain(&self) -> Result<FlushStats, FlushError> {
+        self.request(None, false)
+    }
+
+    /// Sends buffered data like [`Self::flush`], giving up once `deadline` passed
+    ///
+    /// See [`ApiHandle::flush_until`](crate::ApiHandle::flush_until).
+    pub fn flush_until(&self, deadline: Instant) -> Result<(), FlushError> {
+        let deadline = deadline.saturating_duration_since(Instant::now());
+        self.request(Some(deadline), false).map(drop)
+    }
+
+    /// Sends buffered data like [`Self::flush`], then stops accepting data
+    ///
+    /// See [`ApiHandle::shutdown`](crate::ApiHandle::shutdown).
+    pub fn shutdown(&self, deadline: Duration) -> Result<(), FlushError> {
+        self.request(Some(deadline), true).map(drop)
+    }
+
+    fn request(
+        &self,
+        deadline: Option<Duration>,
+        shutdown: bool,
+    ) -> Result<FlushStats, FlushError> {
+        let channel = match self.channel.upgrade() {
+            Some(channel) => channel,
+            // the layer already flushed everything when dropped
+            None => return Ok(FlushStats::default()),
+        };
+
+        let (request, rx) = FlushRequest::new(deadline, shutdown);
+
+        if channel.send(Message::Flush(request)).is_err() {
+            return Err(FlushError::Closed);
+        }
+
+        // the reporting thread exited without replying
+        rx.recv().unwrap_or(Err(FlushError::Closed))
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        // errors are already logged by the reporting thread
+        let _ = self.flush();
+    }
+}
@@ -0,0 +1,106 @@
+use flume::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing_core::LevelFilter;
+
+use crate::api::OverflowPolicy;
+use crate::clock::SystemClock;
+use crate::error::FlushError;
+use crate::fields::FieldConfig;
+use crate::layer::{FlushRequest, Message, NewRelicLayer};
+use crate::stats::{ExporterStats, FlushStats};
+
+/// Handle to an [`Api`](crate::Api) running as a background task, see
+/// [`Api::spawn`](crate::Api::spawn)
+///
+/// Cloning it is cheap, and every clone feeds the same task.
+#[derive(Clone)]
+pub struct ApiHandle {
+    pub(crate) channel: Sender<Message>,
+    pub(crate) receiver: Receiver<Message>,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) stats: ExporterStats,
+}
+
+impl ApiHandle {
+    /// Returns a layer sending its data to the background task
+    pub fn layer(&self) -> NewRelicLayer {
+        NewRelicLayer {
+            handle: None,
+            channel: Some(self.channel.clone()),
+            receiver: self.receiver.clone(),
+            overflow_policy: self.overflow_policy,
+            stats: self.stats.clone(),
+            min_level: LevelFilter::TRACE,
+            fields: FieldConfig::default(),
+            thread_attributes: false,
+            sample_ratio: 1.0,
+            excluded_targets: Vec::new(),
+            clock: Arc::new(SystemClock),
+            max_span_name_len: None,
+            span_name: None,
+        }
+    }
+
+    /// Flushes buffered data, returning once done
+    ///
+    /// Every trace whose root span closed before the call is delivered, or dropped after
+    /// exhausting its retries. Spans still open are not included.
+    ///
+    /// Returns the first error of this flush, the errors of flushes triggered by batch sizes
+    /// or `flush_interval` are only reported to `on_error`.
+    ///
+    /// The flush runs on the tokio runtime the task was spawned on, which must keep running
+    /// until this returns.
+    pub async fn flush(&self) -> Result<(), FlushError> {
+        self.drain().await.map(drop)
+    }
+
+    /// Flushes buffered data like [`Self::flush`], returning how much of it was delivered
+    pub async fn drain(&self) -> Result<FlushStats, FlushError> {
+        self.request(None, false).await
+    }
+
+    /// Flushes buffered data like [`Self::flush`], giving up once `deadline` passed
+    ///
+    /// Data still queued at the deadline is dropped and counted by [`FlushError::Timeout`],
+    /// and persisted to `dead_letter_dir` if configured.
+    pub async fn flush_until(&self, deadline: Instant) -> Result<(), FlushError> {
+        let deadline = deadline.saturating_duration_since(Instant::now());
+        self.request(Some(deadline), false).await.map(drop)
+    }
+
+    /// Flushes buffered data like [`Self::flush`], then stops accepting data
+    ///
+    /// Queued data is abandoned once `deadline` elapsed, as with [`Self::flush_until`]. Data
+    /// sent afterwards is dropped, and later flushes fail with [`FlushError::Closed`].
+    pub async fn shutdown(&self, deadline: Duration) -> Result<(), FlushError> {
+        self.request(Some(deadline), true).await.map(drop)
+    }
+
+    async fn request(
+        &self,
+        deadline: Option<Duration>,
+        shutdown: bool,
+    ) -> Result<FlushStats, FlushError> {
+        let (request, rx) = FlushRequest::new(deadline, shutdown);
+
+        if self
+            .channel
+            .send_async(Message::Flush(request))
+            .await
+            .is_err()
+        {
+            return Err(FlushError::Closed);
+        }
+
+        // the task exited without replying
+        rx.recv_async().await.unwrap_or(Err(FlushError::Closed))
+    }
+
+    /// Returns a handle to the counters of the exporter
+    pub fn stats(&self) -> ExporterStats {
+        self.stats.clone()
+    }
+}
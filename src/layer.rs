@@ -1,19 +1,228 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-use tokio::sync::mpsc::UnboundedSender;
+use flume::{Receiver, Sender, TrySendError};
 use tracing_core::span::{Attributes, Id, Record};
-use tracing_core::{Event, Subscriber};
+use tracing_core::{Event, Level, LevelFilter, Metadata, Subscriber};
 use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
 
+use crate::api::OverflowPolicy;
+use crate::clock::Clock;
+use crate::diag;
+use crate::error::FlushError;
+use crate::fields::FieldConfig;
+use crate::guard::FlushGuard;
+use crate::stats::{ExporterStats, FlushStats};
 use crate::types::{NewrAttributes, NewrCommon, NewrLog, NewrLogs, NewrSpan, NewrSpans, Value};
-use crate::utils::next_trace_id;
+use crate::utils::{current_thread_id, next_trace_id};
+
+/// Messages sent to the reporting thread
+pub(crate) enum Message {
+    Push(NewrLogs, NewrSpans),
+    /// Flushes and replies with the outcome once done
+    Flush(FlushRequest),
+}
+
+/// A flush requested through [`ApiHandle`](crate::ApiHandle) or [`FlushGuard`]
+pub(crate) struct FlushRequest {
+    pub(crate) reply: Sender<Result<FlushStats, FlushError>>,
+    /// Time given to the flush, queued data is abandoned once it elapsed
+    pub(crate) deadline: Option<Duration>,
+    /// Rejects data sent after this flush
+    pub(crate) shutdown: bool,
+}
+
+impl FlushRequest {
+    pub(crate) fn new(
+        deadline: Option<Duration>,
+        shutdown: bool,
+    ) -> (Self, Receiver<Result<FlushStats, FlushError>>) {
+        let (reply, rx) = flume::bounded(1);
+        let request = FlushRequest {
+            reply,
+            deadline,
+            shutdown,
+        };
+        (request, rx)
+    }
+}
 
 /// A [`Layer`] that collects newrelic-compatible data from `tracing` span/event.
 ///
 /// [`Layer`]: tracing_subscriber::layer::Layer
 pub struct NewRelicLayer {
-    pub(crate) channel: Option<UnboundedSender<(NewrLogs, NewrSpans)>>,
+    pub(crate) channel: Option<Sender<Message>>,
+    // used for evicting the oldest message with `OverflowPolicy::DropOldest`
+    pub(crate) receiver: Receiver<Message>,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) stats: ExporterStats,
     pub(crate) handle: Option<JoinHandle<()>>,
+    pub(crate) min_level: LevelFilter,
+    pub(crate) fields: FieldConfig,
+    pub(crate) thread_attributes: bool,
+    pub(crate) sample_ratio: f64,
+    pub(crate) excluded_targets: Vec<String>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) max_span_name_len: Option<usize>,
+    pub(crate) span_name: Option<SpanNameFn>,
+}
+
+/// Normalizes span names, e.g. stripping ids to keep their cardinality low
+pub type SpanNameFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+impl NewRelicLayer {
+    /// Returns a handle to the counters of the exporter
+    pub fn stats(&self) -> ExporterStats {
+        self.stats.clone()
+    }
+
+    /// Returns a guard that flushes buffered data when dropped
+    pub fn flush_guard(&self) -> FlushGuard {
+        FlushGuard {
+            // the channel is only taken when the layer is dropped
+            channel: self
+                .channel
+                .as_ref()
+                .expect("channel is closed")
+                .downgrade(),
+        }
+    }
+
+    /// Sets the minimum level of events exported as logs, spans are always exported
+    pub fn with_min_level(mut self, min_level: impl Into<LevelFilter>) -> Self {
+        self.min_level = min_level.into();
+        self
+    }
+
+    /// Sets the keys of span/event fields whose values are replaced with `[REDACTED]`
+    ///
+    /// Keys are matched case-insensitively.
+    pub fn with_redact_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.fields.redact_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the maximum length in bytes of string values, defaults to 4096
+    ///
+    /// Longer values are truncated and end with `…[truncated]`.
+    pub fn with_max_attribute_len(mut self, max_attribute_len: usize) -> Self {
+        self.fields.max_attribute_len = max_attribute_len;
+        self
+    }
+
+    /// Only exports span/event fields with one of these keys
+    pub fn with_attribute_allowlist<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.fields.allowlist = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never exports span/event fields with one of these keys, even if allowlisted
+    pub fn with_attribute_denylist<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.fields.denylist = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Renames span/event fields, e.g. `req_id` to `request.id`, values are left untouched
+    pub fn with_renames<I, K, V>(mut self, renames: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.fields.renames = renames
+            .into_iter()
+            .map(|(from, to)| (from.into(), to.into()))
+            .collect();
+        self
+    }
+
+    /// Sets the key of the field holding the error of a span or event, defaults to `error`
+    ///
+    /// Its value is sent as `error.message`, with the `error.chain` of sources when recorded
+    /// as a `&dyn Error`. The key is the name in code, see [`Self::with_renames`].
+    pub fn with_error_key(mut self, error_key: impl Into<String>) -> Self {
+        self.fields.error_key = error_key.into();
+        self
+    }
+
+    /// Drops span/event fields recorded as null, an empty string or an empty array
+    pub fn with_drop_empty_attributes(mut self, drop_empty_attributes: bool) -> Self {
+        self.fields.drop_empty = drop_empty_attributes;
+        self
+    }
+
+    /// Sets the target or module path prefixes of events never exported as logs
+    ///
+    /// Events of this crate are always excluded, to avoid feedback loops.
+    pub fn with_excluded_targets<I, T>(mut self, targets: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.excluded_targets = targets.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the ratio of traces exported, from 0.0 to 1.0, defaults to 1.0
+    ///
+    /// Traces are kept or dropped as a whole, together with their logs, based on the random
+    /// bits of their trace id. Traces continuing a `traceparent` follow its sampled flag instead.
+    pub fn with_sample_ratio(mut self, sample_ratio: f64) -> Self {
+        self.sample_ratio = sample_ratio;
+        self
+    }
+
+    /// Sets the clock timestamping spans and logs, defaults to [`SystemClock`](crate::SystemClock)
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets the maximum number of characters of span names, longer ones end with `…`
+    ///
+    /// A limit of zero leaves span names empty.
+    pub fn with_max_span_name_len(mut self, max_span_name_len: usize) -> Self {
+        self.max_span_name_len = Some(max_span_name_len);
+        self
+    }
+
+    /// Sets a function normalizing span names, applied before truncation
+    pub fn with_span_name(
+        mut self,
+        span_name: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.span_name = Some(Arc::new(span_name));
+        self
+    }
+
+    /// Adds `thread.name` and `thread.id` of the emitting thread to spans and logs
+    pub fn with_thread_attributes(mut self, thread_attributes: bool) -> Self {
+        self.thread_attributes = thread_attributes;
+        self
+    }
+
+    /// Sets the maximum number of attributes of a span or log, defaults to 254
+    ///
+    /// Extra fields are dropped and counted in the `nr.attributes.dropped` attribute.
+    pub fn with_max_attributes(mut self, max_attributes: usize) -> Self {
+        self.fields.max_attributes = max_attributes;
+        self
+    }
 }
 
 impl<S> Layer<S> for NewRelicLayer
@@ -25,7 +234,7 @@ where
         let metadata = span.metadata();
 
         // create a new span
-        let mut nr_span = NewrSpan::new(metadata.name().to_string());
+        let mut nr_span = NewrSpan::new(self.span_name(metadata.name()), self.clock.now());
 
         nr_span.attributes.insert(
             "source",
@@ -36,8 +245,13 @@ where
             ),
         );
 
+        if self.thread_attributes {
+            insert_thread_attributes(&mut nr_span.attributes);
+        }
+
         // record span attributes
-        attrs.record(&mut nr_span.attributes);
+        attrs.record(&mut self.fields.visitor(&mut nr_span.attributes));
+        nr_span.take_traceparent();
 
         // insert into extensions
         span.extensions_mut().insert(nr_span);
@@ -48,19 +262,64 @@ where
         let mut extensions = span.extensions_mut();
 
         if let Some(nr_span) = extensions.get_mut::<NewrSpan>() {
-            values.record(&mut nr_span.attributes);
+            values.record(&mut self.fields.visitor(&mut nr_span.attributes));
+            nr_span.take_traceparent();
+        }
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        let follows_id = match ctx.span(follows) {
+            Some(follows) => match follows.extensions().get::<NewrSpan>() {
+                Some(nr_span) => nr_span.id.clone(),
+                None => return,
+            },
+            // the followed span may have already been closed
+            None => return,
+        };
+
+        let span = ctx.span(id).expect("span not found");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(nr_span) = extensions.get_mut::<NewrSpan>() {
+            nr_span.links.push(follows_id);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span not found");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(nr_span) = extensions.get_mut::<NewrSpan>() {
+            nr_span.enter(self.clock.now());
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span not found");
+        let mut extensions = span.extensions_mut();
+
+        if let Some(nr_span) = extensions.get_mut::<NewrSpan>() {
+            nr_span.exit(self.clock.now());
         }
     }
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.is_excluded(event.metadata()) {
+            return;
+        }
+
         // ignore event that is out of current span
         if let Some(id) = ctx.current_span().id() {
             let span = ctx.span(id).expect("span not found");
             let mut extensions = span.extensions_mut();
             let metadata = event.metadata();
 
+            if *metadata.level() > self.min_level {
+                return;
+            }
+
             // create a log
-            let mut nr_log = NewrLog::new(metadata.level());
+            let mut nr_log = NewrLog::new(metadata.level(), self.clock.now());
 
             if let Some(span_id) = extensions.get_mut::<NewrSpan>().map(|s| s.id.clone()) {
                 // add linking metadata
@@ -77,8 +336,40 @@ where
                 ),
             );
 
+            // https://opentelemetry.io/docs/specs/semconv/attributes-registry/code/
+            if let Some(file) = metadata.file() {
+                nr_log.attributes.insert("code.filepath", file);
+            }
+
+            if let Some(line) = metadata.line() {
+                nr_log.attributes.insert("code.lineno", u64::from(line));
+            }
+
+            if let Some(module_path) = metadata.module_path() {
+                nr_log.attributes.insert("code.namespace", module_path);
+            }
+
+            if self.thread_attributes {
+                insert_thread_attributes(&mut nr_log.attributes);
+            }
+
             // record event attributes
-            event.record(&mut nr_log.attributes);
+            event.record(&mut self.fields.visitor(&mut nr_log.attributes));
+
+            // e.g. `error = %err`, the attribute New Relic error tracking looks for,
+            // other values, e.g. `error = true`, are kept as they are
+            let error_key = self.fields.error_attribute();
+            if let Some(Value::String(_)) = nr_log.attributes.0.get(error_key) {
+                if let Some(message) = nr_log.attributes.0.remove(error_key) {
+                    nr_log.attributes.insert("error.message", message);
+                }
+            }
+
+            if *metadata.level() == Level::ERROR {
+                if let Some(nr_span) = extensions.get_mut::<NewrSpan>() {
+                    nr_span.mark_error(nr_log.attributes.0.get("message").cloned());
+                }
+            }
 
             // insert into extensions
             if let Some(nr_logs) = extensions.get_mut::<Vec<NewrLog>>() {
@@ -95,7 +386,15 @@ where
 
         if let Some(mut nr_span) = extensions.remove::<NewrSpan>() {
             // update duration
-            nr_span.update_duration();
+            nr_span.update_duration(self.clock.now());
+            nr_span.set_kind();
+
+            // e.g. `error = %err`, recorded as the message of a flagged span
+            match nr_span.attributes.0.remove(self.fields.error_attribute()) {
+                Some(Value::Bool(error)) => nr_span.attributes.insert("error", error),
+                Some(message) => nr_span.mark_error(Some(message)),
+                None => {}
+            }
 
             let mut logs = extensions.remove::<Vec<NewrLog>>().unwrap_or_default();
 
@@ -131,7 +430,20 @@ where
                 return;
             }
 
-            let trace_id = next_trace_id();
+            // a root span continuing an upstream trace joins it, and follows its decision
+            let (trace_id, sampled) = match spans[0].remote_parent.take() {
+                Some(remote_parent) => {
+                    spans[0]
+                        .attributes
+                        .insert("parent.id", remote_parent.parent_id);
+                    (remote_parent.trace_id, Some(remote_parent.sampled))
+                }
+                None => (next_trace_id(), None),
+            };
+
+            if !sampled.unwrap_or_else(|| self.is_sampled(&trace_id)) {
+                return;
+            }
 
             for span in &mut spans {
                 span.trace_id = Some(trace_id.clone());
@@ -154,8 +466,7 @@ where
                     attributes.insert("hostname", hostname.as_str());
                 }
 
-                // TODO: error handling
-                let _ = channel.send((
+                let message = Message::Push(
                     NewrLogs {
                         logs,
                         common: NewrCommon {
@@ -166,12 +477,135 @@ where
                         spans,
                         common: NewrCommon { attributes },
                     },
-                ));
+                );
+
+                self.send(channel, message);
             }
         }
     }
 }
 
+impl NewRelicLayer {
+    fn span_name(&self, name: &str) -> String {
+        let mut name = match &self.span_name {
+            Some(span_name) => span_name(name),
+            None => name.to_string(),
+        };
+
+        if let Some(max_len) = self.max_span_name_len {
+            if let Some((end, _)) = name.char_indices().nth(max_len) {
+                // the ellipsis counts towards the limit, a zero limit leaves no room for it
+                match name[..end].char_indices().next_back() {
+                    Some((end, _)) => {
+                        name.truncate(end);
+                        name.push('…');
+                    }
+                    None => name.clear(),
+                }
+            }
+        }
+
+        name
+    }
+
+    fn is_excluded(&self, metadata: &Metadata<'_>) -> bool {
+        let is_excluded = |path: &str| {
+            // exporting the exporter's own diagnostics would feed back into itself,
+            // and they may be emitted while extensions of the current span are locked
+            let own = env!("CARGO_CRATE_NAME");
+
+            // not crates sharing the prefix, e.g. `tracing_newrelic_ext`
+            path.strip_prefix(own)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+                || self
+                    .excluded_targets
+                    .iter()
+                    .any(|prefix| path.starts_with(prefix.as_str()))
+        };
+
+        is_excluded(metadata.target()) || metadata.module_path().is_some_and(is_excluded)
+    }
+
+    // https://www.w3.org/TR/trace-context-2/#randomness-of-trace-id
+    fn is_sampled(&self, trace_id: &str) -> bool {
+        if self.sample_ratio >= 1.0 {
+            return true;
+        }
+
+        // the rightmost 7 bytes are random, as with the ratio sampler of OpenTelemetry
+        const RANDOM_BITS: u32 = 56;
+
+        // generated ids are hyphenated uuids, whose last 14 digits are random as well
+        let digits = trace_id
+            .chars()
+            .rev()
+            .filter(|c| *c != '-')
+            .take(RANDOM_BITS as usize / 4)
+            .map(|c| c.to_digit(16).map(u64::from))
+            .collect::<Option<Vec<_>>>()
+            .filter(|digits| digits.len() == RANDOM_BITS as usize / 4);
+
+        let random = match digits {
+            Some(digits) => digits
+                .iter()
+                .rev()
+                .fold(0, |random, digit| random << 4 | digit),
+            // not a hex id, e.g. in tests
+            None => {
+                let mut hasher = DefaultHasher::new();
+                trace_id.hash(&mut hasher);
+                hasher.finish() >> (64 - RANDOM_BITS)
+            }
+        };
+
+        random < (self.sample_ratio * (1u64 << RANDOM_BITS) as f64) as u64
+    }
+
+    fn send(&self, channel: &Sender<Message>, mut message: Message) {
+        match self.overflow_policy {
+            OverflowPolicy::DropNewest => {
+                if let Err(TrySendError::Full(_)) = channel.try_send(message) {
+                    diag::warn!("queue is full, dropping newest trace");
+                }
+            }
+
+            OverflowPolicy::DropOldest => {
+                while let Err(TrySendError::Full(returned)) = channel.try_send(message) {
+                    message = returned;
+
+                    match self.receiver.try_recv() {
+                        Ok(Message::Push(..)) => {
+                            diag::warn!("queue is full, dropping oldest trace");
+                        }
+                        // a flush is never dropped, its guard would wait forever
+                        Ok(flush @ Message::Flush(_)) => {
+                            let _ = channel.send(flush);
+                            diag::warn!("queue is full, dropping newest trace");
+                            return;
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+
+            OverflowPolicy::Block => {
+                let _ = channel.send(message);
+            }
+        }
+    }
+}
+
+// https://opentelemetry.io/docs/specs/semconv/attributes-registry/thread/
+fn insert_thread_attributes(attributes: &mut NewrAttributes) {
+    let thread = std::thread::current();
+
+    if let Some(name) = thread.name() {
+        attributes.insert("thread.name", name);
+    }
+
+    attributes.insert("thread.id", current_thread_id());
+}
+
 impl Drop for NewRelicLayer {
     fn drop(&mut self) {
         if let Some(channel) = self.channel.take() {
@@ -183,3 +617,101 @@ impl Drop for NewRelicLayer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::ApiHandle;
+
+    fn push() -> Message {
+        Message::Push(
+            NewrLogs {
+                logs: Vec::new(),
+                common: NewrCommon {
+                    attributes: NewrAttributes::default(),
+                },
+            },
+            NewrSpans {
+                spans: Vec::new(),
+                common: NewrCommon {
+                    attributes: NewrAttributes::default(),
+                },
+            },
+        )
+    }
+
+    #[test]
+    fn samples_on_trace_id_bits() {
+        let (channel, receiver) = flume::bounded(1);
+        let mut layer = ApiHandle {
+            channel,
+            receiver,
+            overflow_policy: OverflowPolicy::DropNewest,
+            stats: ExporterStats::default(),
+        }
+        .layer()
+        .with_sample_ratio(0.5);
+
+        assert!(layer.is_sampled("4bf92f3577b34da6a300000000000000"));
+        assert!(layer.is_sampled("4bf92f3577b34da6a37fffffffffffff"));
+        assert!(!layer.is_sampled("4bf92f3577b34da6a380000000000000"));
+        assert!(!layer.is_sampled("000000000000000000ffffffffffffff"));
+        assert!(layer.is_sampled("0af76519-16cd-43dd-8400-000000000000"));
+        assert!(!layer.is_sampled("0af76519-16cd-43dd-84ff-ffffffffffff"));
+
+        layer.sample_ratio = 0.0;
+        assert!(!layer.is_sampled("4bf92f3577b34da6a300000000000000"));
+        layer.sample_ratio = 1.0;
+        assert!(layer.is_sampled("4bf92f3577b34da6a3ffffffffffffff"));
+    }
+
+    #[test]
+    fn truncates_span_names() {
+        let (channel, receiver) = flume::bounded(1);
+        let layer = ApiHandle {
+            channel,
+            receiver,
+            overflow_policy: OverflowPolicy::DropNewest,
+            stats: ExporterStats::default(),
+        }
+        .layer();
+
+        let long = "é".repeat(500);
+
+        let layer = layer.with_max_span_name_len(100);
+        let name = layer.span_name(&long);
+        assert_eq!(name.chars().count(), 100);
+        assert!(name.ends_with("é…"));
+        assert_eq!(layer.span_name("short"), "short");
+
+        let layer = layer.with_max_span_name_len(1);
+        assert_eq!(layer.span_name(&long), "…");
+
+        let layer = layer.with_max_span_name_len(0);
+        assert_eq!(layer.span_name(&long), "");
+    }
+
+    #[test]
+    fn drop_oldest_keeps_flushes() {
+        let (channel, receiver) = flume::bounded(2);
+        let layer = ApiHandle {
+            channel: channel.clone(),
+            receiver: receiver.clone(),
+            overflow_policy: OverflowPolicy::DropOldest,
+            stats: ExporterStats::default(),
+        }
+        .layer();
+
+        let (request, _) = FlushRequest::new(None, false);
+        layer.send(&channel, Message::Flush(request));
+        layer.send(&channel, push());
+        layer.send(&channel, push());
+        layer.send(&channel, push());
+
+        let queued: Vec<_> = receiver.drain().collect();
+        assert_eq!(queued.len(), 2);
+        assert!(queued
+            .iter()
+            .any(|message| matches!(message, Message::Flush(_))));
+    }
+}
@@ -6,6 +6,8 @@
 //!
 //! `tracing::Span` will be tried as Trace Span, and `tracing::Event` as Logs.
 //!
+//! `tracing::Event` with an `eventType` field will be tried as Custom Event, if [`Api::account_id`] is set.
+//!
 //! `tracing::Attribute` and `tracing::Metadata` wil be tried as Custom Attributes.
 //!
 //! [`tracing`]: https://github.com/tokio-rs/tracing
@@ -95,6 +97,24 @@
 //!
 //!     <img src="https://raw.githubusercontent.com/PoiScript/tracing-newrelic/a/screenshot/transactions.jpg"  alt="newrelic transactions"  width="614" height="326"  />
 //!
+//! # Features
+//!
+//! The TLS backend of the internal http client is selected with one of:
+//!
+//! - `default-tls`, enabled by default: the platform's native TLS
+//! - `native-tls`: same as `default-tls`, named explicitly
+//! - `rustls-tls`: pure Rust TLS, e.g. for static musl binaries
+//!
+//! Use `default-features = false` with `rustls-tls` to avoid linking the native one.
+//!
+//! Optional features:
+//!
+//! - `simd`: serializes payloads with [`simd-json`](https://docs.rs/simd-json) instead of
+//!   `serde_json`
+//! - `hostname`: detects the `host.name` attribute of logs and spans
+//! - `internal-tracing`: emits the exporter's own diagnostics through `tracing` instead of
+//!   `log`, they are never exported themselves
+//!
 //! # License
 //!
 //! MIT
@@ -102,23 +122,69 @@
 #![warn(missing_docs)]
 
 mod api;
+mod builder;
+mod clock;
+mod context;
+mod diag;
+mod error;
+mod fields;
+mod guard;
+mod handle;
 mod layer;
+mod stats;
+#[cfg(feature = "__testing")]
+mod testing;
+// only reachable through `__bench`
+#[cfg_attr(feature = "__bench", allow(missing_docs))]
 mod types;
 mod utils;
 
-pub use api::{Api, ApiEndpoint};
-pub use layer::NewRelicLayer;
+pub use api::{
+    Api, ApiEndpoint, ErrorCallback, KeyHandle, LogFormat, Mirror, OverflowPolicy, PayloadCallback,
+    RetryPredicate, SentCallback, Signal, Transport,
+};
+pub use builder::ApiBuilder;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use context::TraceParent;
+pub use error::{ApiError, BuilderError, FlushError, ParseEndpointError, ParseTraceParentError};
+pub use flate2::Compression;
+pub use guard::FlushGuard;
+pub use handle::ApiHandle;
+pub use layer::{NewRelicLayer, SpanNameFn};
+pub use stats::{ExporterStats, FlushStats};
 
+#[cfg(feature = "__bench")]
+#[doc(hidden)]
+pub mod __bench {
+    pub use crate::api::to_gz;
+    pub use crate::types::{NewrAttributes, NewrCommon, NewrLog, NewrLogs};
+}
+
+#[cfg(feature = "__testing")]
+#[doc(hidden)]
+pub mod __testing {
+    pub use crate::testing::{MockTransport, Received};
+}
+
+use fields::FieldConfig;
+use std::sync::Arc;
 use std::thread;
 use tokio::runtime;
-use tokio::sync::mpsc::unbounded_channel;
-use types::{NewrLogs, NewrSpans};
+use tracing_core::LevelFilter;
 
 /// Create a new NewRelic layer and spawn a thread for sending data
+///
+/// The thread runs its own tokio runtime, so the application doesn't need one. The exporter
+/// itself only runs on tokio, as the http client, timers and a custom [`Transport`] are driven
+/// by that runtime. async-std isn't supported as an alternative, an application using it
+/// reports through this thread, while [`Api::spawn`] requires a tokio runtime.
 pub fn layer(api: impl Into<Api>) -> NewRelicLayer {
-    let mut api = api.into();
+    let api = api.into();
 
-    let (tx, mut rx) = unbounded_channel::<(NewrLogs, NewrSpans)>();
+    let (tx, rx) = api.queue();
+    let overflow_policy = api.overflow_policy;
+    let stats = api.stats();
+    let worker_rx = rx.clone();
 
     let handle = thread::Builder::new()
         .name("newrelic-report".into())
@@ -131,13 +197,7 @@ pub fn layer(api: impl Into<Api>) -> NewRelicLayer {
                 Ok(v) => v,
             };
 
-            rt.block_on(async move {
-                while let Some((logs, spans)) = rx.recv().await {
-                    api.push(logs, spans).await
-                }
-
-                api.flush().await;
-            });
+            rt.block_on(api.run(worker_rx));
 
             drop(rt);
         })
@@ -146,5 +206,16 @@ pub fn layer(api: impl Into<Api>) -> NewRelicLayer {
     NewRelicLayer {
         handle: Some(handle),
         channel: Some(tx),
+        receiver: rx,
+        overflow_policy,
+        stats,
+        min_level: LevelFilter::TRACE,
+        fields: FieldConfig::default(),
+        thread_attributes: false,
+        sample_ratio: 1.0,
+        excluded_targets: Vec::new(),
+        clock: Arc::new(SystemClock),
+        max_span_name_len: None,
+        span_name: None,
     }
 }
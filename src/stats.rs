@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Counters describing the health of the exporter
+///
+/// Cloning it returns a handle to the same counters, so it can be kept after passing
+/// [`Api`](crate::Api) to [`layer`](crate::layer()).
+#[derive(Clone, Default)]
+pub struct ExporterStats(Arc<Counters>);
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) logs_sent: AtomicU64,
+    pub(crate) spans_sent: AtomicU64,
+    pub(crate) metrics_sent: AtomicU64,
+    pub(crate) events_sent: AtomicU64,
+    pub(crate) batches_failed: AtomicU64,
+    pub(crate) retries: AtomicU64,
+    pub(crate) queue_depth: AtomicUsize,
+}
+
+impl ExporterStats {
+    /// Number of logs delivered
+    pub fn logs_sent(&self) -> u64 {
+        self.0.logs_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of spans delivered
+    pub fn spans_sent(&self) -> u64 {
+        self.0.spans_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of metrics delivered
+    pub fn metrics_sent(&self) -> u64 {
+        self.0.metrics_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of custom events delivered
+    pub fn events_sent(&self) -> u64 {
+        self.0.events_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of batches dropped after failing to be delivered
+    pub fn batches_failed(&self) -> u64 {
+        self.0.batches_failed.load(Ordering::Relaxed)
+    }
+
+    /// Number of retried requests
+    pub fn retries(&self) -> u64 {
+        self.0.retries.load(Ordering::Relaxed)
+    }
+
+    /// Number of traces buffered for the next flush
+    pub fn queue_depth(&self) -> usize {
+        self.0.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn counters(&self) -> &Counters {
+        &self.0
+    }
+}
+
+/// Number of items delivered by [`ApiHandle::drain`](crate::ApiHandle::drain)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlushStats {
+    /// Number of logs delivered
+    pub logs_sent: u64,
+    /// Number of spans delivered
+    pub spans_sent: u64,
+    /// Number of metrics delivered
+    pub metrics_sent: u64,
+    /// Number of custom events delivered
+    pub events_sent: u64,
+}
+
+impl From<&Counters> for FlushStats {
+    fn from(counters: &Counters) -> Self {
+        FlushStats {
+            logs_sent: counters.logs_sent.load(Ordering::Relaxed),
+            spans_sent: counters.spans_sent.load(Ordering::Relaxed),
+            metrics_sent: counters.metrics_sent.load(Ordering::Relaxed),
+            events_sent: counters.events_sent.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use futures_util::future::BoxFuture;
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use reqwest::{Method, Request, Response};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+use crate::api::{from_gz, Transport};
+use crate::error::ApiError;
+
+/// A request received by [`MockTransport`]
+pub struct Received {
+    /// Method of the request
+    pub method: Method,
+    /// Url the request was sent to
+    pub url: String,
+    /// Headers of the request
+    pub headers: HeaderMap,
+    /// Body as sent, e.g. gzipped
+    pub body: Vec<u8>,
+    /// Body once decompressed
+    pub payload: Vec<u8>,
+    /// Body parsed as json
+    pub json: serde_json::Value,
+    /// When the request was sent, following the paused clock of tokio
+    pub at: Instant,
+}
+
+/// Answers requests with queued statuses, 202 once exhausted, recording them
+///
+/// Responses carry a `requestId` counting the requests, unless another body is set.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    statuses: Arc<Mutex<VecDeque<u16>>>,
+    received: Arc<Mutex<Vec<Received>>>,
+    // requests received in total
+    count: Arc<AtomicUsize>,
+    body: Option<serde_json::Value>,
+    delay: Duration,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+impl MockTransport {
+    /// Answers the first requests with `statuses`
+    pub fn new(statuses: &[u16]) -> Self {
+        let transport = MockTransport::default();
+        transport.statuses.lock().unwrap().extend(statuses);
+        transport
+    }
+
+    /// Answers every request with `body`
+    pub fn with_body(mut self, body: serde_json::Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// Answers every request after `delay`
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Takes the requests received so far
+    pub fn received(&self) -> Vec<Received> {
+        std::mem::take(&mut *self.received.lock().unwrap())
+    }
+
+    /// Number of requests received since the last [`Self::received`]
+    pub fn len(&self) -> usize {
+        self.received.lock().unwrap().len()
+    }
+
+    /// Whether no requests were received since the last [`Self::received`]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of requests answered at once
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Takes the attributes of the logs received so far, in the detailed format
+    pub fn logs(&self) -> Vec<serde_json::Value> {
+        self.batches("log", "logs")
+            .map(|log| log["attributes"].clone())
+            .collect()
+    }
+
+    /// Takes the spans received so far
+    pub fn spans(&self) -> Vec<serde_json::Value> {
+        self.batches("trace", "spans").collect()
+    }
+
+    /// Takes the metrics received so far
+    pub fn metrics(&self) -> Vec<serde_json::Value> {
+        self.batches("metric", "metrics").collect()
+    }
+
+    // items of the batches sent to urls containing `url`
+    fn batches<'a>(
+        &self,
+        url: &'a str,
+        items: &'a str,
+    ) -> impl Iterator<Item = serde_json::Value> + 'a {
+        self.received()
+            .into_iter()
+            .filter(move |received| received.url.contains(url))
+            .flat_map(|received| received.json.as_array().cloned().unwrap_or_default())
+            .flat_map(move |batch| batch[items].as_array().cloned().unwrap_or_default())
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: Request) -> BoxFuture<'_, Result<Response, ApiError>> {
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .unwrap_or_default()
+            .to_vec();
+
+        let payload = if request.headers().contains_key(CONTENT_ENCODING) {
+            from_gz(&body).unwrap()
+        } else {
+            body.clone()
+        };
+
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let response_body = match &self.body {
+            Some(body) => body.clone(),
+            None => serde_json::json!({ "requestId": format!("request_{count}") }),
+        };
+
+        self.received.lock().unwrap().push(Received {
+            method: request.method().clone(),
+            url: request.url().to_string(),
+            headers: request.headers().clone(),
+            json: serde_json::from_slice(&payload).unwrap(),
+            body,
+            payload,
+            at: Instant::now(),
+        });
+
+        let status = self.statuses.lock().unwrap().pop_front().unwrap_or(202);
+
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+        Box::pin(async move {
+            if !self.delay.is_zero() {
+                sleep(self.delay).await;
+            }
+
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            let response = http::Response::builder()
+                .status(status)
+                .body(response_body.to_string())
+                .unwrap();
+
+            Ok(Response::from(response))
+        })
+    }
+}
@@ -1,13 +1,13 @@
 use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::time::{Instant, SystemTime};
-use tracing_core::field::{Field, Visit};
+use std::time::{Duration, SystemTime};
 use tracing_core::Level;
 
-use crate::utils::{next_span_id, now, serialize_system_time};
+use crate::context::TraceParent;
+use crate::utils::{next_span_id, serialize_system_time};
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Value {
     I64(i64),
@@ -15,6 +15,20 @@ pub enum Value {
     F64(f64),
     Bool(bool),
     String(String),
+    Json(serde_json::Value),
+}
+
+impl Value {
+    /// Whether the value is null, an empty string or an empty array
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Value::String(s) => s.is_empty(),
+            Value::Json(serde_json::Value::Null) => true,
+            Value::Json(serde_json::Value::String(s)) => s.is_empty(),
+            Value::Json(serde_json::Value::Array(a)) => a.is_empty(),
+            _ => false,
+        }
+    }
 }
 
 impl From<i64> for Value {
@@ -47,13 +61,19 @@ impl From<String> for Value {
     }
 }
 
+impl From<serde_json::Value> for Value {
+    fn from(i: serde_json::Value) -> Self {
+        Value::Json(i)
+    }
+}
+
 impl From<&str> for Value {
     fn from(i: &str) -> Self {
         Value::String(i.to_string())
     }
 }
 
-#[derive(Serialize, Default, Clone, Debug)]
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
 pub struct NewrAttributes(pub HashMap<String, Value>);
 
 impl NewrAttributes {
@@ -62,32 +82,6 @@ impl NewrAttributes {
     }
 }
 
-impl Visit for NewrAttributes {
-    fn record_bool(&mut self, field: &Field, value: bool) {
-        self.insert(field.name(), value);
-    }
-
-    fn record_i64(&mut self, field: &Field, value: i64) {
-        self.insert(field.name(), value);
-    }
-
-    fn record_f64(&mut self, field: &Field, value: f64) {
-        self.insert(field.name(), value);
-    }
-
-    fn record_u64(&mut self, field: &Field, value: u64) {
-        self.insert(field.name(), value);
-    }
-
-    fn record_str(&mut self, field: &Field, value: &str) {
-        self.insert(field.name(), value.to_string());
-    }
-
-    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
-        self.insert(field.name(), format!("{:?}", value));
-    }
-}
-
 #[derive(Serialize, Debug)]
 pub struct NewrSpan {
     /// Unique identifier for this span.
@@ -98,31 +92,111 @@ pub struct NewrSpan {
     #[serde(serialize_with = "serialize_system_time")]
     /// Span start time in milliseconds since the Unix epoch.
     pub timestamp: SystemTime,
-    /// Instant the span was created.
+    /// Time spent inside the span, between enters and exits.
+    #[serde(skip)]
+    pub busy: Duration,
+    /// Time the span was last entered, if currently entered.
     #[serde(skip)]
-    pub instant: Instant,
+    pub entered: Option<SystemTime>,
+    /// Ids of the spans this span follows from.
+    #[serde(skip)]
+    pub links: Vec<String>,
+    /// Upstream context this span continues, from its `traceparent` field.
+    #[serde(skip)]
+    pub remote_parent: Option<TraceParent>,
     /// Any set of key: value pairs that add more details about a span.
     pub attributes: NewrAttributes,
 }
 
 impl NewrSpan {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, now: SystemTime) -> Self {
         let mut attributes = NewrAttributes::default();
         attributes.insert("name", name);
 
         NewrSpan {
             id: next_span_id(),
             trace_id: None,
-            timestamp: now(),
-            instant: Instant::now(),
+            timestamp: now,
+            busy: Duration::ZERO,
+            entered: None,
+            links: Vec::new(),
+            remote_parent: None,
             attributes,
         }
     }
 
-    pub fn update_duration(&mut self) {
-        let duration = self.instant.elapsed();
+    /// Flags the span as errored, New Relic counts it in error rates.
+    pub fn mark_error(&mut self, message: Option<Value>) {
+        self.attributes.insert("error", true);
+
+        if let Some(message) = message {
+            self.attributes
+                .0
+                .entry("error.message".into())
+                .or_insert(message);
+        }
+    }
+
+    /// Sets `span.kind` from the `otel.kind` attribute, defaulting to `internal`.
+    // https://docs.newrelic.com/docs/distributed-tracing/concepts/how-new-relic-distributed-tracing-works/#span-kind
+    pub fn set_kind(&mut self) {
+        let attributes = &mut self.attributes.0;
+
+        if attributes.contains_key("span.kind") {
+            return;
+        }
+
+        let kind = match attributes.get("otel.kind") {
+            Some(Value::String(kind)) => kind.to_ascii_lowercase(),
+            _ => "internal".into(),
+        };
+
+        self.attributes.insert("span.kind", kind);
+    }
+
+    /// Takes the `traceparent` attribute as the upstream context of the span.
+    pub fn take_traceparent(&mut self) {
+        if let Some(Value::String(traceparent)) = self.attributes.0.remove("traceparent") {
+            match traceparent.parse() {
+                Ok(remote_parent) => self.remote_parent = Some(remote_parent),
+                Err(error) => crate::diag::warn!("ignoring {}", error),
+            }
+        }
+    }
+
+    pub fn enter(&mut self, now: SystemTime) {
+        // nested enters of the same span are counted once
+        if self.entered.is_none() {
+            self.entered = Some(now);
+        }
+    }
+
+    pub fn exit(&mut self, now: SystemTime) {
+        if let Some(entered) = self.entered.take() {
+            self.busy += now.duration_since(entered).unwrap_or_default();
+        }
+    }
+
+    pub fn update_duration(&mut self, now: SystemTime) {
+        // a span closed while entered is busy until now
+        self.exit(now);
+
+        // the wall clock may go backwards
+        let duration = now.duration_since(self.timestamp).unwrap_or_default();
         let duration_ms = duration.as_secs_f64() * 1000.0;
         self.attributes.insert("duration.ms", duration_ms);
+
+        if !self.links.is_empty() {
+            let links = std::mem::take(&mut self.links);
+            self.attributes
+                .insert("nr.links", serde_json::Value::from(links));
+        }
+
+        let busy = self.busy.min(duration);
+        self.attributes
+            .insert("busy.ms", busy.as_secs_f64() * 1000.0);
+        self.attributes
+            .insert("idle.ms", (duration - busy).as_secs_f64() * 1000.0);
     }
 }
 
@@ -140,16 +214,60 @@ pub struct NewrLog {
 }
 
 impl NewrLog {
-    pub fn new(level: &Level) -> Self {
+    pub fn new(level: &Level, now: SystemTime) -> Self {
         NewrLog {
-            timestamp: now(),
+            timestamp: now,
             logtype: "accesslogs",
             attributes: NewrAttributes::default(),
-            level: level.as_str(),
+            level: nr_level(level),
         }
     }
 }
 
+/// New Relic log level of a `tracing` level
+#[inline]
+pub fn nr_level(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARN",
+        Level::INFO => "INFO",
+        Level::DEBUG => "DEBUG",
+        Level::TRACE => "TRACE",
+    }
+}
+
+/// A custom event, sent to the Event Api
+#[derive(Serialize, Debug)]
+pub struct NewrEvent {
+    #[serde(rename = "eventType")]
+    pub event_type: String,
+    #[serde(serialize_with = "serialize_system_time")]
+    pub timestamp: SystemTime,
+    #[serde(flatten)]
+    pub attributes: NewrAttributes,
+}
+
+impl NewrEvent {
+    /// Whether the log carries a string `eventType` attribute, and is sent as an event
+    pub fn is_event(log: &NewrLog) -> bool {
+        matches!(log.attributes.0.get("eventType"), Some(Value::String(_)))
+    }
+
+    /// Converts a log carrying a string `eventType` attribute
+    pub fn from_log(mut log: NewrLog) -> Option<Self> {
+        let event_type = match log.attributes.0.remove("eventType")? {
+            Value::String(event_type) => event_type,
+            _ => return None,
+        };
+
+        Some(NewrEvent {
+            event_type,
+            timestamp: log.timestamp,
+            attributes: log.attributes,
+        })
+    }
+}
+
 #[derive(Serialize)]
 pub struct NewrCommon {
     pub attributes: NewrAttributes,
@@ -161,8 +279,227 @@ pub struct NewrLogs {
     pub common: NewrCommon,
 }
 
+impl NewrLogs {
+    /// Flattens the logs, merging the common attributes into each of them
+    pub fn simple(&self) -> impl Iterator<Item = NewrSimpleLog<'_>> {
+        self.logs.iter().map(move |log| {
+            let mut attributes: HashMap<&str, &Value> = self
+                .common
+                .attributes
+                .0
+                .iter()
+                .map(|(key, value)| (key.as_str(), value))
+                .collect();
+
+            // attributes of the log take precedence
+            attributes.extend(
+                log.attributes
+                    .0
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value)),
+            );
+
+            NewrSimpleLog {
+                timestamp: log.timestamp,
+                logtype: log.logtype,
+                level: log.level,
+                attributes,
+            }
+        })
+    }
+}
+
+/// A log of the simple format of the Log Api
+#[derive(Serialize)]
+pub struct NewrSimpleLog<'a> {
+    #[serde(serialize_with = "serialize_system_time")]
+    pub timestamp: SystemTime,
+    pub logtype: &'static str,
+    pub level: &'static str,
+    #[serde(flatten)]
+    pub attributes: HashMap<&'a str, &'a Value>,
+}
+
 #[derive(Serialize)]
 pub struct NewrSpans {
     pub spans: Vec<NewrSpan>,
     pub common: NewrCommon,
 }
+
+impl NewrSpans {
+    /// Trace id shared by the spans
+    pub fn trace_id(&self) -> Option<&str> {
+        self.spans.first()?.trace_id.as_deref()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct NewrMetric {
+    pub name: String,
+    /// `gauge`, `count` or `summary`
+    // https://docs.newrelic.com/docs/data-apis/understand-data/metric-data/metric-data-type/
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub value: Value,
+    #[serde(serialize_with = "serialize_system_time")]
+    pub timestamp: SystemTime,
+    pub attributes: NewrAttributes,
+}
+
+impl NewrMetric {
+    pub fn gauge(name: String, value: Value, timestamp: SystemTime) -> Self {
+        NewrMetric {
+            name,
+            kind: "gauge",
+            value,
+            timestamp,
+            attributes: NewrAttributes::default(),
+        }
+    }
+
+    pub fn count(name: String, value: Value, timestamp: SystemTime) -> Self {
+        NewrMetric {
+            name,
+            kind: "count",
+            value,
+            timestamp,
+            attributes: NewrAttributes::default(),
+        }
+    }
+
+    pub fn summary(name: String, summary: &DurationSummary, timestamp: SystemTime) -> Self {
+        NewrMetric {
+            name,
+            kind: "summary",
+            value: serde_json::json!({
+                "count": summary.count,
+                "sum": summary.sum,
+                "min": summary.min,
+                "max": summary.max,
+            })
+            .into(),
+            timestamp,
+            attributes: NewrAttributes::default(),
+        }
+    }
+}
+
+/// Aggregated durations in milliseconds of spans sharing a name
+#[derive(Debug)]
+pub struct DurationSummary {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl DurationSummary {
+    pub fn new(duration_ms: f64) -> Self {
+        DurationSummary {
+            count: 1,
+            sum: duration_ms,
+            min: duration_ms,
+            max: duration_ms,
+        }
+    }
+
+    pub fn record(&mut self, duration_ms: f64) {
+        self.count += 1;
+        self.sum += duration_ms;
+        self.min = self.min.min(duration_ms);
+        self.max = self.max.max(duration_ms);
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewrMetrics {
+    pub metrics: Vec<NewrMetric>,
+    pub common: NewrMetricsCommon,
+}
+
+/// Window shared by the metrics of a batch
+#[derive(Serialize)]
+pub struct NewrMetricsCommon {
+    /// Start of the window
+    #[serde(serialize_with = "serialize_system_time")]
+    pub timestamp: SystemTime,
+    /// Length of the window of `count` and `summary` metrics
+    #[serde(rename = "interval.ms")]
+    pub interval_ms: u64,
+    pub attributes: NewrAttributes,
+}
+
+impl NewrMetric {
+    /// Creates a gauge for every numeric attribute of spans
+    pub fn gauges_from_spans(spans: &NewrSpans) -> Vec<Self> {
+        let mut metrics = Vec::new();
+
+        for span in &spans.spans {
+            for (key, value) in &span.attributes.0 {
+                if !matches!(value, Value::I64(_) | Value::U64(_) | Value::F64(_)) {
+                    continue;
+                }
+
+                let mut metric = NewrMetric::gauge(key.clone(), value.clone(), span.timestamp);
+
+                if let Some(name) = span.attributes.0.get("name") {
+                    metric.attributes.insert("span.name", name.clone());
+                }
+
+                // batches of metrics don't share the common block of spans
+                for (key, value) in &spans.common.attributes.0 {
+                    metric
+                        .attributes
+                        .0
+                        .entry(key.clone())
+                        .or_insert_with(|| value.clone());
+                }
+
+                metrics.push(metric);
+            }
+        }
+
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn maps_every_level() {
+        let levels = [
+            (Level::ERROR, "ERROR"),
+            (Level::WARN, "WARN"),
+            (Level::INFO, "INFO"),
+            (Level::DEBUG, "DEBUG"),
+            (Level::TRACE, "TRACE"),
+        ];
+
+        for (level, expected) in &levels {
+            assert_eq!(nr_level(level), *expected);
+            assert_eq!(NewrLog::new(level, UNIX_EPOCH).level, *expected);
+        }
+    }
+
+    #[test]
+    fn summaries_serialize_their_aggregates() {
+        let mut summary = DurationSummary::new(10.0);
+        summary.record(30.0);
+        summary.record(20.0);
+
+        let metric = NewrMetric::summary("span.duration".into(), &summary, UNIX_EPOCH);
+        assert_eq!(
+            serde_json::to_value(&metric).unwrap(),
+            serde_json::json!({
+                "name": "span.duration",
+                "type": "summary",
+                "value": { "count": 3, "sum": 60.0, "min": 10.0, "max": 30.0 },
+                "timestamp": 0,
+                "attributes": {},
+            })
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use serde::Serializer;
 use std::{
     convert::TryInto as _,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use uuid::Uuid;
 
@@ -11,7 +11,7 @@ pub fn next_trace_id() -> String {
         use std::cell::RefCell;
 
         thread_local! {
-            static COUNT: RefCell<i32> = RefCell::new(0);
+            static COUNT: RefCell<i32> = const { RefCell::new(0) };
         }
 
         COUNT.with(|count| {
@@ -29,7 +29,7 @@ pub fn next_span_id() -> String {
         use std::cell::RefCell;
 
         thread_local! {
-            static COUNT: RefCell<i32> = RefCell::new(0);
+            static COUNT: RefCell<i32> = const { RefCell::new(0) };
         }
 
         COUNT.with(|count| {
@@ -63,3 +63,86 @@ where
         s.serialize_none()
     }
 }
+
+/// Parses the value of a `Retry-After` header, either delay-seconds or HTTP-date
+///
+/// https://www.rfc-editor.org/rfc/rfc9110#field.retry-after
+#[inline]
+pub fn parse_retry_after(val: &str) -> Option<Duration> {
+    let val = val.trim();
+
+    if let Ok(seconds) = val.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = httpdate::parse_http_date(val).ok()?;
+
+    // a date in the past means we can retry immediately
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[inline]
+pub fn default_service_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .as_ref()
+        .and_then(|path| path.file_stem())
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .unwrap_or_else(|| "unknown_service".into())
+}
+
+#[inline]
+pub fn default_host_name() -> Option<String> {
+    #[cfg(feature = "hostname")]
+    {
+        hostname::get().ok()?.into_string().ok()
+    }
+
+    #[cfg(not(feature = "hostname"))]
+    {
+        None
+    }
+}
+
+/// Process-unique id of the current thread, assigned on first use
+#[inline]
+pub fn current_thread_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    thread_local! {
+        static THREAD_ID: u64 = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+
+    THREAD_ID.with(|id| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_delta_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let future = SystemTime::now() + Duration::from_secs(120);
+        let delay = parse_retry_after(&httpdate::fmt_http_date(future)).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+
+        // a date in the past can be retried immediately
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,639 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::{Api, ApiEndpoint, ApiError, ApiHandle, FlushError, Signal};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+fn mock_api(transport: &MockTransport) -> Api {
+    let mut api = Api::default().with_transport(transport.clone());
+    api.retry_base = Duration::from_millis(1);
+    api.jitter = false;
+    api.logs_enabled = false;
+    api
+}
+
+// closes a span without events, only the trace api is called as logs are off
+async fn send_span(handle: &ApiHandle) -> Result<(), FlushError> {
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+    });
+    handle.flush().await
+}
+
+fn temp_dir() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    std::env::temp_dir().join(format!("tracing-newrelic-{}-{nanos}", std::process::id()))
+}
+
+#[tokio::test]
+async fn retries_until_accepted() {
+    let transport = MockTransport::new(&[503, 429, 202]);
+    let api = mock_api(&transport);
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap();
+
+    let received = transport.received();
+    assert_eq!(received.len(), 3);
+    assert!(received
+        .iter()
+        .all(|request| request.json == received[0].json));
+    assert_eq!(stats.retries(), 2);
+    assert_eq!(stats.spans_sent(), 1);
+    assert_eq!(stats.batches_failed(), 0);
+}
+
+#[tokio::test]
+async fn stats_count_delivered_items() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.logs_enabled = true;
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        let _child = tracing::info_span!("query").entered();
+        for _ in 0..3 {
+            tracing::info!("queried");
+        }
+    });
+    handle.flush().await.unwrap();
+
+    assert_eq!(stats.logs_sent(), 3);
+    assert_eq!(stats.spans_sent(), 2);
+    assert_eq!(stats.retries(), 0);
+    assert_eq!(stats.batches_failed(), 0);
+    assert_eq!(stats.queue_depth(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_delays_grow() {
+    let transport = MockTransport::new(&[503, 503, 503]);
+    let mut api = mock_api(&transport);
+    api.retry_base = Duration::from_secs(1);
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap();
+
+    let received = transport.received();
+    assert_eq!(received.len(), 4);
+    let delays: Vec<_> = received
+        .windows(2)
+        .map(|pair| pair[1].at - pair[0].at)
+        .collect();
+    assert_eq!(delays, [1, 2, 4].map(Duration::from_secs),);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_retries() {
+    let transport = MockTransport::new(&[503; 5]);
+    let mut api = mock_api(&transport);
+    api.max_retries = 2;
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(
+        error,
+        FlushError::RetriesExhausted {
+            status: 503,
+            retries: 2,
+            dropped: 1
+        }
+    ));
+
+    assert_eq!(transport.received().len(), 3);
+    assert_eq!(stats.spans_sent(), 0);
+    assert_eq!(stats.batches_failed(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_after_max_retry_elapsed() {
+    let transport = MockTransport::new(&[503; 10]);
+    let mut api = mock_api(&transport);
+    api.retry_base = Duration::from_secs(1);
+    api.max_retries = 10;
+    api.max_retry_elapsed = Some(Duration::from_secs(5));
+    let handle = api.spawn();
+
+    let start = tokio::time::Instant::now();
+    let error = send_span(&handle).await.unwrap_err();
+
+    // retried after 1, 3 and 7 seconds, given up once failing for longer than 5
+    assert!(matches!(
+        error,
+        FlushError::RetriesExhausted {
+            status: 503,
+            retries: 3,
+            dropped: 1
+        }
+    ));
+    assert_eq!(transport.received().len(), 4);
+    assert_eq!(start.elapsed(), Duration::from_secs(7));
+}
+
+#[tokio::test]
+async fn on_error_counts_dropped_items() {
+    let transport = MockTransport::new(&[503; 4]);
+    let mut api = mock_api(&transport);
+    api.logs_enabled = true;
+    api.max_retries = 1;
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let reported = dropped.clone();
+    api.on_error = Some(Arc::new(move |error: &FlushError| {
+        reported.lock().unwrap().push(error.dropped());
+    }));
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        for _ in 0..2 {
+            let _child = tracing::info_span!("query").entered();
+            tracing::info!("queried");
+        }
+    });
+    handle.flush().await.unwrap_err();
+
+    // the two logs and the three spans of the trace
+    let mut dropped = dropped.lock().unwrap().clone();
+    dropped.sort_unstable();
+    assert_eq!(dropped, [2, 3]);
+    assert_eq!(transport.received().len(), 4);
+}
+
+#[tokio::test]
+async fn rejected_batches_are_not_retried() {
+    let transport = MockTransport::new(&[400]);
+    let api = mock_api(&transport);
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(
+        error,
+        FlushError::Rejected {
+            status: 400,
+            dropped: 1,
+            ..
+        }
+    ));
+
+    assert_eq!(transport.received().len(), 1);
+    assert_eq!(stats.retries(), 0);
+    assert_eq!(stats.batches_failed(), 1);
+}
+
+#[tokio::test]
+async fn retry_if_overrides_retryable_errors() {
+    let transport = MockTransport::new(&[400, 400]);
+    let mut api = mock_api(&transport);
+    api.retry_if = Some(Arc::new(|error: &ApiError| {
+        matches!(error, ApiError::Rejected(400)) || error.is_retryable()
+    }));
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap();
+
+    assert_eq!(transport.received().len(), 3);
+    assert_eq!(stats.retries(), 2);
+    assert_eq!(stats.spans_sent(), 1);
+}
+
+#[tokio::test]
+async fn circuit_opens_after_failures() {
+    let transport = MockTransport::new(&[503]);
+    let mut api = mock_api(&transport);
+    api.max_retries = 0;
+    api.circuit_threshold = Some(1);
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(error, FlushError::RetriesExhausted { .. }));
+    assert_eq!(transport.received().len(), 1);
+
+    // dropped without calling the endpoint while the circuit is open
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(error, FlushError::CircuitOpen { dropped: 1 }));
+    assert_eq!(transport.received().len(), 0);
+    assert_eq!(stats.batches_failed(), 2);
+}
+
+#[tokio::test]
+async fn dead_letters_are_replayed() {
+    let dir = temp_dir();
+
+    let failing = MockTransport::new(&[503]);
+    let mut api = mock_api(&failing);
+    api.max_retries = 0;
+    api.dead_letter_dir = Some(dir.clone());
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap_err();
+
+    let dropped = failing.received();
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    let accepting = MockTransport::new(&[]);
+    let mut api = mock_api(&accepting);
+    api.dead_letter_dir = Some(dir.clone());
+    api.replay_dead_letters().await.unwrap();
+
+    let replayed = accepting.received();
+    assert_eq!(replayed.len(), 1);
+    assert_eq!(replayed[0].url, dropped[0].url);
+    assert_eq!(replayed[0].json, dropped[0].json);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    fs::remove_dir(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn unauthorized_batches_are_not_retried() {
+    let transport = MockTransport::new(&[403]);
+    let api = mock_api(&transport);
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(
+        error,
+        FlushError::Unauthorized {
+            status: 403,
+            dropped: 1
+        }
+    ));
+    assert_eq!(transport.received().len(), 1);
+    assert_eq!(stats.retries(), 0);
+    assert_eq!(stats.batches_failed(), 1);
+}
+
+#[tokio::test]
+async fn invalid_urls_are_network_errors() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.max_retries = 0;
+    api.trace_endpoint = ApiEndpoint::Custom("http://exa mple.com".to_owned());
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(error, FlushError::Network { dropped: 1, .. }));
+    assert!(transport.is_empty());
+}
+
+#[tokio::test]
+async fn timeouts_are_retried() {
+    // accepts connections without ever answering
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((connection, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            connections.push(connection);
+        }
+    });
+
+    let mut api = Api::default();
+    api.trace_endpoint = ApiEndpoint::LocalForwarder(addr);
+    api.logs_enabled = false;
+    api.request_timeout = Duration::from_millis(50);
+    api.retry_base = Duration::from_millis(1);
+    api.jitter = false;
+    api.max_retries = 2;
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    let error = send_span(&handle).await.unwrap_err();
+    assert!(matches!(error, FlushError::Network { ref error, dropped: 1 } if error.is_timeout()));
+    assert_eq!(stats.retries(), 2);
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn coalesces_traces_sharing_attributes() {
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.coalesce_traces = true;
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("first", traceparent = TRACEPARENT).entered();
+    });
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!(
+            "billing",
+            traceparent = TRACEPARENT,
+            service.name = "billing"
+        )
+        .entered();
+    });
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("second", traceparent = TRACEPARENT).entered();
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    assert_eq!(received.len(), 1);
+
+    let entries = received[0].json.as_array().unwrap();
+    let names = |entry: &serde_json::Value| -> Vec<serde_json::Value> {
+        entry["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|span| span["attributes"]["name"].clone())
+            .collect()
+    };
+    assert_eq!(entries.len(), 2);
+    assert_eq!(names(&entries[0]), ["first", "second"]);
+    assert_eq!(names(&entries[1]), ["billing"]);
+    assert_eq!(
+        entries[1]["common"]["attributes"]["service.name"],
+        "billing"
+    );
+}
+
+#[tokio::test]
+async fn large_logs_are_split_by_size() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.logs_enabled = true;
+    api.traces_enabled = false;
+    api.log_batch_size = 100;
+    api.max_payload_bytes = 5_000;
+    let handle = api.spawn();
+
+    let text = "x".repeat(1_000);
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for _ in 0..20 {
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!(text = text.as_str(), "large");
+        }
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    assert!(received.len() > 1);
+    assert!(received
+        .iter()
+        .all(|request| request.payload.len() <= 5_000));
+
+    let logs: usize = received
+        .iter()
+        .flat_map(|request| request.json.as_array().unwrap())
+        .map(|entry| entry["logs"].as_array().unwrap().len())
+        .sum();
+    assert_eq!(logs, 20);
+}
+
+#[tokio::test(start_paused = true)]
+async fn flushes_once_the_interval_elapsed() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.flush_interval = Duration::from_secs(5);
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+    });
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert!(transport.is_empty());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(transport.spans().len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn sub_batches_are_sent_concurrently() {
+    let transport = MockTransport::new(&[]).with_delay(Duration::from_secs(1));
+    let mut api = mock_api(&transport);
+    // a trace per request
+    api.max_payload_bytes = 1;
+    api.max_concurrency = 3;
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for _ in 0..6 {
+            let _span = tracing::info_span!("request").entered();
+        }
+    });
+
+    let start = tokio::time::Instant::now();
+    handle.flush().await.unwrap();
+
+    // two rounds of three requests
+    assert_eq!(start.elapsed(), Duration::from_secs(2));
+    assert_eq!(transport.max_in_flight(), 3);
+    assert_eq!(transport.spans().len(), 6);
+}
+
+#[tokio::test]
+async fn warnings_are_reported() {
+    let transport = MockTransport::new(&[]).with_body(serde_json::json!({
+        "requestId": "request_1",
+        "warnings": ["attribute value too long"],
+    }));
+    let mut api = mock_api(&transport);
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let warnings = reported.clone();
+    api.on_error = Some(Arc::new(move |error: &FlushError| {
+        if let FlushError::Warnings {
+            request_id,
+            warnings: messages,
+        } = error
+        {
+            warnings
+                .lock()
+                .unwrap()
+                .push((request_id.clone(), messages.clone()));
+        }
+    }));
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    // still delivered
+    send_span(&handle).await.unwrap();
+    assert_eq!(stats.spans_sent(), 1);
+
+    assert_eq!(
+        *reported.lock().unwrap(),
+        [(
+            Some("request_1".to_owned()),
+            vec!["attribute value too long".to_owned()]
+        )]
+    );
+}
+
+#[tokio::test]
+async fn too_large_batches_are_split() {
+    let transport = MockTransport::new(&[413]);
+    let api = mock_api(&transport);
+    let stats = api.stats();
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for _ in 0..4 {
+            let _span = tracing::info_span!("request").entered();
+        }
+    });
+    handle.flush().await.unwrap();
+
+    // the rejected batch is sent again in halves
+    let traces: Vec<_> = transport
+        .received()
+        .iter()
+        .map(|request| request.json.as_array().unwrap().len())
+        .collect();
+    assert_eq!(traces, [4, 2, 2]);
+    assert_eq!(stats.spans_sent(), 4);
+    assert_eq!(stats.retries(), 0);
+    assert_eq!(stats.batches_failed(), 0);
+}
+
+#[tokio::test]
+async fn rotated_keys_are_used_by_later_requests() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.key = "old".to_owned();
+    let keys = api.key_handle();
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap();
+    keys.set("new".to_owned());
+    send_span(&handle).await.unwrap();
+
+    let received = transport.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].headers["Api-Key"], "old");
+    assert_eq!(received[1].headers["Api-Key"], "new");
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_are_spaced_by_the_rate_limit() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.max_payload_bytes = 1;
+    api.max_requests_per_second = Some(1.0);
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for _ in 0..4 {
+            let _span = tracing::info_span!("request").entered();
+        }
+    });
+    handle.flush().await.unwrap();
+
+    // delayed rather than dropped, even though sent concurrently
+    let received = transport.received();
+    assert_eq!(received.len(), 4);
+    for pair in received.windows(2) {
+        assert!(pair[1].at - pair[0].at >= Duration::from_secs(1));
+    }
+    assert!(received[3].at - received[0].at < Duration::from_secs(4));
+}
+
+// traces per request of three flushes of 8 traces, the first one answered with `statuses`
+async fn adaptive_batches(statuses: &[u16]) -> Vec<Vec<usize>> {
+    let transport = MockTransport::new(statuses);
+    let mut api = mock_api(&transport);
+    api.adaptive_batch = Some((1, 8));
+    api.span_batch_size = 100;
+    let handle = api.spawn();
+
+    let mut flushes = Vec::new();
+    for _ in 0..3 {
+        tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+            for _ in 0..8 {
+                let _span = tracing::info_span!("request").entered();
+            }
+        });
+        handle.flush().await.unwrap();
+
+        flushes.push(
+            transport
+                .received()
+                .iter()
+                .map(|request| request.json.as_array().unwrap().len())
+                .collect(),
+        );
+    }
+    flushes
+}
+
+#[tokio::test]
+async fn adaptive_batches_shrink_and_grow_back() {
+    // halved after being throttled, then growing by one per accepted request
+    assert_eq!(
+        adaptive_batches(&[429]).await,
+        [vec![8, 4, 4], vec![6, 2], vec![8]]
+    );
+    assert_eq!(
+        adaptive_batches(&[413]).await,
+        [vec![8, 4, 4], vec![6, 2], vec![8]]
+    );
+    assert_eq!(adaptive_batches(&[]).await, [[8], [8], [8]]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn in_flight_requests_are_limited_across_signals() {
+    let transport = MockTransport::new(&[]).with_delay(Duration::from_secs(1));
+    let mut api = mock_api(&transport);
+    api.logs_enabled = true;
+    api.max_payload_bytes = 1;
+    api.max_in_flight = Some(2);
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for _ in 0..4 {
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!("handled");
+        }
+    });
+    handle.flush().await.unwrap();
+
+    // four concurrent requests per signal, two at once overall
+    assert_eq!(transport.max_in_flight(), 2);
+    assert_eq!(transport.received().len(), 8);
+}
+
+#[tokio::test]
+async fn request_ids_are_reported() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let reported = sent.clone();
+    api.on_sent = Some(Arc::new(move |signal, request_id: Option<&str>| {
+        reported
+            .lock()
+            .unwrap()
+            .push((signal, request_id.map(str::to_owned)));
+    }));
+    let handle = api.spawn();
+
+    send_span(&handle).await.unwrap();
+    send_span(&handle).await.unwrap();
+
+    assert_eq!(
+        *sent.lock().unwrap(),
+        [
+            (Signal::Spans, Some("request_1".to_owned())),
+            (Signal::Spans, Some("request_2".to_owned()))
+        ]
+    );
+}
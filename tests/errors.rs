@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::Api;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[derive(Debug)]
+struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+impl Error for Timeout {}
+
+#[derive(Debug)]
+struct Query(Timeout);
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "query failed")
+    }
+}
+
+impl Error for Query {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[tokio::test]
+async fn renamed_error_key() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = handle
+        .layer()
+        .with_error_key("failure")
+        .with_renames([("failure", "cause")]);
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::info_span!("request").entered();
+        let error = Query(Timeout);
+        tracing::error!(failure = &error as &dyn Error, "request failed");
+    });
+    handle.flush().await.unwrap();
+
+    let logs = transport.logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["error.message"], "query failed");
+    assert_eq!(logs[0]["error.chain"], serde_json::json!(["timed out"]));
+    assert!(logs[0].get("error.class").is_none());
+    assert!(logs[0].get("cause").is_none());
+    assert!(logs[0].get("failure").is_none());
+}
+
+#[tokio::test]
+async fn keeps_non_string_error_fields() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::warn!(error = true, "request failed");
+        tracing::warn!(error = "timed out", "request failed");
+    });
+    handle.flush().await.unwrap();
+
+    let logs = transport.logs();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0]["error"], true);
+    assert!(logs[0].get("error.message").is_none());
+    assert_eq!(logs[1]["error.message"], "timed out");
+    assert!(logs[1].get("error").is_none());
+}
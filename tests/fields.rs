@@ -0,0 +1,129 @@
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::{Api, NewRelicLayer};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+// attributes of the single log recorded by `emit` through the configured layer
+async fn log_attributes(
+    configure: impl FnOnce(NewRelicLayer) -> NewRelicLayer,
+    emit: impl FnOnce(),
+) -> serde_json::Value {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = configure(handle.layer());
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::info_span!("request").entered();
+        emit();
+    });
+    handle.flush().await.unwrap();
+
+    let mut logs = transport.logs();
+    assert_eq!(logs.len(), 1);
+    logs.remove(0)
+}
+
+#[tokio::test]
+async fn redacts_keys_case_insensitively() {
+    let attributes = log_attributes(
+        |layer| layer.with_redact_keys(["PASSWORD"]),
+        || tracing::info!(user = "alice", password = "hunter2", "signed in"),
+    )
+    .await;
+
+    assert_eq!(attributes["password"], "[REDACTED]");
+    assert_eq!(attributes["user"], "alice");
+}
+
+#[tokio::test]
+async fn renames_keys() {
+    let attributes = log_attributes(
+        |layer| {
+            layer
+                .with_renames([("user", "enduser.id")])
+                .with_redact_keys(["enduser.id"])
+        },
+        || tracing::info!(user = "alice", "signed in"),
+    )
+    .await;
+
+    // redaction also applies to the new key
+    assert_eq!(attributes["enduser.id"], "[REDACTED]");
+    assert!(attributes.get("user").is_none());
+}
+
+#[tokio::test]
+async fn allowlist_and_denylist() {
+    let attributes = log_attributes(
+        |layer| {
+            layer
+                .with_attribute_allowlist(["user", "password", "message"])
+                .with_attribute_denylist(["password"])
+                .with_renames([("user", "enduser.id")])
+        },
+        || tracing::info!(user = "alice", password = "hunter2", tries = 3, "signed in"),
+    )
+    .await;
+
+    // lists apply to the name in code
+    assert_eq!(attributes["enduser.id"], "alice");
+    assert_eq!(attributes["message"], "signed in");
+    assert!(attributes.get("password").is_none());
+    assert!(attributes.get("tries").is_none());
+}
+
+#[tokio::test]
+async fn drops_empty_values() {
+    let attributes = log_attributes(
+        |layer| layer.with_drop_empty_attributes(true),
+        || tracing::info!(user = "alice", note = "", "signed in"),
+    )
+    .await;
+
+    assert_eq!(attributes["user"], "alice");
+    assert!(attributes.get("note").is_none());
+
+    let attributes = log_attributes(|layer| layer, || tracing::info!(note = "", "signed in")).await;
+
+    assert_eq!(attributes["note"], "");
+}
+
+#[tokio::test]
+async fn truncates_long_values() {
+    let attributes = log_attributes(
+        |layer| layer.with_max_attribute_len(16),
+        || tracing::info!(short = "ok", long = "ééééééééééééé", "signed in"),
+    )
+    .await;
+
+    // cut on a char boundary, within the limit once marked
+    assert_eq!(attributes["long"], "é…[truncated]");
+    assert_eq!(attributes["short"], "ok");
+}
+
+#[tokio::test]
+async fn truncates_below_the_marker_len() {
+    let attributes = log_attributes(
+        |layer| layer.with_max_attribute_len(5),
+        || tracing::info!(long = "ééé", "signed in"),
+    )
+    .await;
+
+    assert_eq!(attributes["long"], "éé");
+    assert_eq!(attributes["message"], "signe");
+}
+
+#[tokio::test]
+async fn counts_dropped_attributes() {
+    let attributes = log_attributes(
+        // span.id, source and code attributes are recorded first
+        |layer| layer.with_max_attributes(8),
+        || tracing::info!(a = 1, b = 2, c = 3, "signed in"),
+    )
+    .await;
+
+    assert_eq!(attributes["message"], "signed in");
+    assert_eq!(attributes["a"], 1);
+    assert_eq!(attributes["b"], 2);
+    assert!(attributes.get("c").is_none());
+    assert_eq!(attributes["nr.attributes.dropped"], 1);
+}
@@ -0,0 +1,271 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::Dispatch;
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::{Api, FlushError, FlushStats, OverflowPolicy};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+fn mock_api(transport: &MockTransport) -> Api {
+    Api::default().with_transport(transport.clone())
+}
+
+#[tokio::test]
+async fn drain_delivers_queued_logs() {
+    let transport = MockTransport::new(&[]);
+    let handle = mock_api(&transport).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        for i in 0..3 {
+            tracing::info!(i, "queued");
+        }
+    });
+    assert!(transport.is_empty());
+
+    let stats = handle.drain().await.unwrap();
+    assert_eq!(
+        stats,
+        FlushStats {
+            logs_sent: 3,
+            spans_sent: 1,
+            ..FlushStats::default()
+        }
+    );
+    assert_eq!(transport.logs().len(), 3);
+
+    // only counts what the last flush delivered
+    assert_eq!(handle.drain().await.unwrap(), FlushStats::default());
+}
+
+#[test]
+fn guard_drain_delivers_queued_logs() {
+    let transport = MockTransport::new(&[]);
+    let layer = tracing_newrelic::layer(mock_api(&transport));
+    let guard = layer.flush_guard();
+
+    // kept alive, as dropping the layer flushes too
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        let _span = tracing::info_span!("request").entered();
+        for i in 0..3 {
+            tracing::info!(i, "queued");
+        }
+    });
+    assert!(transport.is_empty());
+
+    assert_eq!(guard.drain().unwrap().logs_sent, 3);
+    assert_eq!(transport.logs().len(), 3);
+}
+
+#[test]
+fn dropping_the_guard_delivers_queued_logs() {
+    let transport = MockTransport::new(&[]);
+    let layer = tracing_newrelic::layer(mock_api(&transport));
+    let guard = layer.flush_guard();
+
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("queued");
+    });
+    assert!(transport.is_empty());
+
+    // blocks until the reporting thread flushed
+    drop(guard);
+    assert_eq!(transport.logs().len(), 1);
+}
+
+#[tokio::test]
+async fn shutdown_rejects_later_data() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let stats = handle.stats();
+
+    let send_log = || {
+        tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!("queued");
+        })
+    };
+
+    send_log();
+    handle.shutdown(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(stats.logs_sent(), 1);
+    assert_eq!(transport.logs().len(), 1);
+
+    send_log();
+    assert!(matches!(handle.flush().await, Err(FlushError::Closed)));
+    assert!(matches!(
+        handle.shutdown(Duration::from_secs(1)).await,
+        Err(FlushError::Closed)
+    ));
+    assert!(transport.is_empty());
+    assert_eq!(stats.logs_sent(), 1);
+}
+
+#[test]
+fn guard_shutdown_rejects_later_flushes() {
+    let transport = MockTransport::new(&[]);
+    let layer = tracing_newrelic::layer(Api::default().with_transport(transport.clone()));
+    let guard = layer.flush_guard();
+
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("queued");
+    });
+
+    guard.shutdown(Duration::from_secs(1)).unwrap();
+    assert_eq!(transport.logs().len(), 1);
+    assert!(matches!(guard.flush(), Err(FlushError::Closed)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_until_reports_queued_data() {
+    let slow = MockTransport::new(&[]).with_delay(Duration::from_secs(10));
+    let handle = Api::default().with_transport(slow.clone()).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("queued");
+    });
+
+    let start = Instant::now();
+    let error = handle
+        .flush_until(start + Duration::from_secs(1))
+        .await
+        .unwrap_err();
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+    // the log and its span
+    assert!(matches!(
+        error,
+        FlushError::Timeout {
+            deadline,
+            dropped: 2
+        } if deadline == Duration::from_secs(1)
+    ));
+    assert_eq!(handle.stats().logs_sent(), 0);
+
+    // nothing is left to send afterwards
+    assert_eq!(handle.drain().await.unwrap(), FlushStats::default());
+}
+
+#[tokio::test(start_paused = true)]
+async fn flush_until_succeeds_before_deadline() {
+    let transport = MockTransport::new(&[]).with_delay(Duration::from_millis(100));
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("queued");
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    handle.flush_until(deadline).await.unwrap();
+    assert_eq!(transport.logs().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn handles_are_shared_by_concurrent_tasks() {
+    let transport = MockTransport::new(&[]);
+    let mut api = mock_api(&transport);
+    api.log_batch_size = 1_000;
+    api.span_batch_size = 1_000;
+    let handle = api.spawn();
+
+    let tasks: Vec<_> = (0..8)
+        .map(|task| {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let layer = handle.layer();
+                tracing::subscriber::with_default(Registry::default().with(layer), || {
+                    for i in 0..10 {
+                        let _span = tracing::info_span!("request", task, i).entered();
+                        tracing::info!("handled");
+                    }
+                });
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+    handle.flush().await.unwrap();
+
+    assert_eq!(handle.stats().logs_sent(), 80);
+    assert_eq!(handle.stats().spans_sent(), 80);
+    assert_eq!(transport.logs().len(), 80);
+}
+
+// traces sent while the task can't run on this thread, only the queue's capacity is kept
+async fn queued_spans(max_queue_len: usize, overflow_policy: OverflowPolicy) -> Vec<String> {
+    let transport = MockTransport::new(&[]);
+    let mut api = Api::default().with_transport(transport.clone());
+    api.max_queue_len = max_queue_len;
+    api.overflow_policy = overflow_policy;
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        for i in 0..3 {
+            let _span = tracing::info_span!("request", i).entered();
+        }
+    });
+    handle.flush().await.unwrap();
+
+    transport
+        .spans()
+        .iter()
+        .map(|span| span["attributes"]["i"].to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn queue_is_bounded() {
+    assert_eq!(queued_spans(2, OverflowPolicy::DropNewest).await.len(), 2);
+    // rather than a rendezvous channel dropping everything
+    assert_eq!(queued_spans(0, OverflowPolicy::DropNewest).await.len(), 1);
+}
+
+#[tokio::test]
+async fn full_queues_drop_newest_traces() {
+    assert_eq!(
+        queued_spans(2, OverflowPolicy::DropNewest).await,
+        ["0", "1"]
+    );
+}
+
+#[tokio::test]
+async fn full_queues_drop_oldest_traces() {
+    assert_eq!(
+        queued_spans(2, OverflowPolicy::DropOldest).await,
+        ["1", "2"]
+    );
+}
+
+#[tokio::test]
+async fn spawn_does_not_block_on_full_queues() {
+    assert_eq!(queued_spans(2, OverflowPolicy::Block).await, ["0", "1"]);
+}
+
+#[test]
+fn full_queues_block_until_sent() {
+    let transport = MockTransport::new(&[]).with_delay(Duration::from_millis(10));
+    let mut api = mock_api(&transport);
+    api.max_queue_len = 1;
+    api.overflow_policy = OverflowPolicy::Block;
+    api.logs_enabled = false;
+    let layer = tracing_newrelic::layer(api);
+    let guard = layer.flush_guard();
+
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        for _ in 0..5 {
+            let _span = tracing::info_span!("request").entered();
+        }
+    });
+
+    guard.flush().unwrap();
+    assert_eq!(transport.spans().len(), 5);
+}
@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing_newrelic::{Api, ApiEndpoint, ApiHandle};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+// a minimal http server answering 202, passing on the head of every request
+async fn serve() -> (SocketAddr, UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(answer(stream, tx.clone()));
+        }
+    });
+
+    (addr, rx)
+}
+
+async fn answer(mut stream: TcpStream, heads: UnboundedSender<String>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let end = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..end]).to_lowercase();
+    let len: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map_or(0, |len| len.trim().parse().unwrap());
+
+    while buf.len() < end + len {
+        let n = stream.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let _ = heads.send(head);
+
+    let body = r#"{"requestId":"request"}"#;
+    let response = format!(
+        "HTTP/1.1 202 Accepted\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+}
+
+async fn send_span(handle: &ApiHandle) {
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+    });
+    handle.flush().await.unwrap();
+}
+
+#[tokio::test]
+async fn custom_user_agent_is_sent() {
+    let (addr, mut heads) = serve().await;
+
+    let client = reqwest::Client::builder()
+        .user_agent("checkout/1.0")
+        .build()
+        .unwrap();
+    let mut api = Api::default().with_client(client);
+    api.trace_endpoint = ApiEndpoint::LocalForwarder(addr);
+    api.logs_enabled = false;
+    let handle = api.spawn();
+
+    send_span(&handle).await;
+
+    let head = heads.recv().await.unwrap();
+    assert!(head.starts_with("post /trace/v1 "));
+    assert!(head.contains("\r\nuser-agent: checkout/1.0\r\n"));
+}
+
+#[tokio::test]
+async fn requests_go_through_the_proxy() {
+    let (addr, mut heads) = serve().await;
+
+    let proxy = reqwest::Proxy::http(format!("http://{}", addr)).unwrap();
+    let mut api = Api::default().with_proxy(proxy).unwrap();
+    api.trace_endpoint = ApiEndpoint::Custom("http://newrelic.invalid".to_owned());
+    api.logs_enabled = false;
+    let handle = api.spawn();
+
+    send_span(&handle).await;
+
+    // proxies get the absolute url of the endpoint
+    let head = heads.recv().await.unwrap();
+    assert!(head.starts_with("post http://newrelic.invalid/trace/v1 "));
+    assert!(head.contains("\r\nhost: newrelic.invalid\r\n"));
+}
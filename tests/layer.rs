@@ -0,0 +1,333 @@
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::Level;
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::{Api, ManualClock, NewRelicLayer};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[tokio::test]
+async fn excludes_own_events_only() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!(target: "tracing_newrelic", "own");
+        tracing::info!(target: "tracing_newrelic::api", "own module");
+        tracing::info!(target: "tracing_newrelic_ext", "other crate");
+    });
+    handle.flush().await.unwrap();
+
+    let logs = transport.logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["message"], "other crate");
+}
+
+#[tokio::test]
+async fn timestamps_are_epoch_millis() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456));
+    let layer = handle.layer().with_clock(clock);
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("signed in");
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let log = received
+        .iter()
+        .find(|request| request.url.contains("log"))
+        .unwrap();
+    let span = received
+        .iter()
+        .find(|request| request.url.contains("trace"))
+        .unwrap();
+    assert_eq!(log.json[0]["logs"][0]["timestamp"], 1_600_000_000_123u64);
+    assert_eq!(span.json[0]["spans"][0]["timestamp"], 1_600_000_000_123u64);
+}
+
+// span attributes of the trace `emit` sends, timed by `clock`
+async fn timed_span(clock: &ManualClock, emit: impl FnOnce()) -> serde_json::Value {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = handle.layer().with_clock(clock.clone());
+
+    tracing::subscriber::with_default(Registry::default().with(layer), emit);
+    handle.flush().await.unwrap();
+
+    transport.spans()[0]["attributes"].clone()
+}
+
+#[tokio::test]
+async fn span_duration_follows_the_clock() {
+    let clock = ManualClock::new(UNIX_EPOCH);
+
+    let attributes = timed_span(&clock, || {
+        let _span = tracing::info_span!("request").entered();
+        clock.advance(Duration::from_millis(1_500));
+    })
+    .await;
+
+    assert_eq!(attributes["duration.ms"], 1_500.0);
+}
+
+#[tokio::test]
+async fn span_time_is_split_into_busy_and_idle() {
+    let clock = ManualClock::new(UNIX_EPOCH);
+
+    let attributes = timed_span(&clock, || {
+        let span = tracing::info_span!("request");
+        clock.advance(Duration::from_millis(10));
+        span.in_scope(|| clock.advance(Duration::from_millis(30)));
+        clock.advance(Duration::from_millis(5));
+    })
+    .await;
+
+    assert_eq!(attributes["duration.ms"], 45.0);
+    assert_eq!(attributes["busy.ms"], 30.0);
+    assert_eq!(attributes["idle.ms"], 15.0);
+}
+
+#[tokio::test]
+async fn non_string_event_types_stay_logs() {
+    let transport = MockTransport::new(&[]);
+    let mut api = Api::default().with_transport(transport.clone());
+    api.account_id = Some("1234".to_owned());
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!(eventType = "Purchase", amount = 3, "purchased");
+        tracing::info!(eventType = 42, "not an event");
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let events = received
+        .iter()
+        .find(|request| request.url.contains("events"))
+        .unwrap();
+    assert_eq!(events.json.as_array().unwrap().len(), 1);
+    assert_eq!(events.json[0]["eventType"], "Purchase");
+
+    let log = received
+        .iter()
+        .find(|request| request.url.contains("log"))
+        .unwrap();
+    let logs = log.json[0]["logs"].as_array().unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["attributes"]["eventType"], 42);
+}
+
+#[tokio::test]
+async fn events_below_the_min_level_are_dropped() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = handle.layer().with_min_level(Level::INFO);
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::debug_span!("request").entered();
+        tracing::debug!("verbose");
+        tracing::info!("kept");
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let logs = &received
+        .iter()
+        .find(|request| request.url.contains("log"))
+        .unwrap()
+        .json[0]["logs"];
+    assert_eq!(logs.as_array().unwrap().len(), 1);
+    assert_eq!(logs[0]["attributes"]["message"], "kept");
+
+    // spans are still exported
+    let spans = &received
+        .iter()
+        .find(|request| request.url.contains("trace"))
+        .unwrap()
+        .json[0]["spans"];
+    assert_eq!(spans[0]["attributes"]["name"], "request");
+}
+
+// logs and spans sent for what `emit` traces through the layer returned by `configure`
+async fn export(
+    configure: impl FnOnce(NewRelicLayer) -> NewRelicLayer,
+    emit: impl FnOnce(),
+) -> (Vec<serde_json::Value>, Vec<serde_json::Value>) {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = configure(handle.layer());
+
+    tracing::subscriber::with_default(Registry::default().with(layer), emit);
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let items = |path: &str, items: &str| -> Vec<serde_json::Value> {
+        received
+            .iter()
+            .filter(|request| request.url.contains(path))
+            .flat_map(|request| request.json.as_array().unwrap())
+            .flat_map(|batch| batch[items].as_array().unwrap().clone())
+            .collect()
+    };
+
+    (items("/log/v1", "logs"), items("/trace/v1", "spans"))
+}
+
+fn named<'a>(spans: &'a [serde_json::Value], name: &str) -> &'a serde_json::Value {
+    spans
+        .iter()
+        .find(|span| span["attributes"]["name"] == name)
+        .unwrap_or_else(|| panic!("no span named {}", name))
+}
+
+#[tokio::test]
+async fn follows_from_links_spans() {
+    let (_, spans) = export(
+        |layer| layer,
+        || {
+            let cause = tracing::info_span!("cause");
+            let effect = tracing::info_span!("effect");
+            effect.follows_from(&cause);
+        },
+    )
+    .await;
+
+    let cause = named(&spans, "cause");
+    let effect = named(&spans, "effect");
+    assert_eq!(
+        effect["attributes"]["nr.links"],
+        serde_json::json!([cause["id"]])
+    );
+    assert!(cause["attributes"].get("nr.links").is_none());
+}
+
+#[tokio::test]
+async fn error_events_flag_their_span() {
+    let (_, spans) = export(
+        |layer| layer,
+        || {
+            {
+                let _span = tracing::info_span!("failing").entered();
+                tracing::error!("query failed");
+            }
+            let _span = tracing::info_span!("slow").entered();
+            tracing::warn!("query took long");
+        },
+    )
+    .await;
+
+    let failing = &named(&spans, "failing")["attributes"];
+    assert_eq!(failing["error"], true);
+    assert_eq!(failing["error.message"], "query failed");
+
+    let slow = &named(&spans, "slow")["attributes"];
+    assert!(slow.get("error").is_none());
+    assert!(slow.get("error.message").is_none());
+}
+
+#[tokio::test]
+async fn logs_have_code_attributes() {
+    let mut line = 0;
+    let (logs, _) = export(
+        |layer| layer,
+        || {
+            let _span = tracing::info_span!("request").entered();
+            line = line!() + 1;
+            tracing::info!("signed in");
+        },
+    )
+    .await;
+
+    let attributes = &logs[0]["attributes"];
+    assert_eq!(attributes["code.filepath"], file!());
+    assert_eq!(attributes["code.lineno"], line);
+    assert_eq!(attributes["code.namespace"], module_path!());
+}
+
+// traces what `emit` does on a thread named `worker`
+fn on_worker(emit: impl FnOnce() + Send + 'static) {
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+    std::thread::Builder::new()
+        .name("worker".to_owned())
+        .spawn(move || tracing::dispatcher::with_default(&dispatch, emit))
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn worker_request() {
+    on_worker(|| {
+        let _span = tracing::info_span!("request").entered();
+        tracing::info!("signed in");
+    });
+}
+
+#[tokio::test]
+async fn thread_attributes_are_opt_in() {
+    let (logs, spans) = export(|layer| layer.with_thread_attributes(true), worker_request).await;
+
+    for attributes in [&logs[0]["attributes"], &spans[0]["attributes"]] {
+        assert_eq!(attributes["thread.name"], "worker");
+        assert!(attributes["thread.id"].is_u64());
+    }
+    assert_eq!(
+        logs[0]["attributes"]["thread.id"],
+        spans[0]["attributes"]["thread.id"]
+    );
+
+    let (logs, spans) = export(|layer| layer, worker_request).await;
+    for attributes in [&logs[0]["attributes"], &spans[0]["attributes"]] {
+        assert!(attributes.get("thread.name").is_none());
+        assert!(attributes.get("thread.id").is_none());
+    }
+}
+
+#[cfg(feature = "internal-tracing")]
+#[tokio::test]
+async fn internal_diagnostics_are_not_exported() {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let _default = tracing::subscriber::set_default(Registry::default().with(handle.layer()));
+
+    tracing::info_span!("first").in_scope(|| tracing::info!("queued"));
+
+    {
+        let span = tracing::info_span!("request");
+        let _entered = span.enter();
+        tracing::info!("handled");
+
+        // the worker reports flushing the first trace while this span is current
+        handle.flush().await.unwrap();
+    }
+    handle.flush().await.unwrap();
+
+    let messages: Vec<_> = transport
+        .logs()
+        .iter()
+        .map(|log| log["message"].clone())
+        .collect();
+    assert_eq!(messages, ["queued", "handled"]);
+}
+
+#[tokio::test]
+async fn span_kind_defaults_to_internal() {
+    let (_, spans) = export(
+        |layer| layer,
+        || {
+            tracing::info_span!("handler", otel.kind = "SERVER").in_scope(|| {});
+            tracing::info_span!("call", span.kind = "client", otel.kind = "server").in_scope(|| {});
+            tracing::info_span!("compute").in_scope(|| {});
+        },
+    )
+    .await;
+
+    let kind = |name| named(&spans, name)["attributes"]["span.kind"].clone();
+    assert_eq!(kind("handler"), "server");
+    // an explicit kind is kept
+    assert_eq!(kind("call"), "client");
+    assert_eq!(kind("compute"), "internal");
+}
@@ -0,0 +1,363 @@
+use reqwest::header::CONTENT_ENCODING;
+use reqwest::Method;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing_newrelic::__testing::{MockTransport, Received};
+use tracing_newrelic::{Api, ApiBuilder, LogFormat, ManualClock, Signal};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+// requests sent for a trace whose root span is emitting `emit`
+async fn requests(api: Api, emit: impl FnOnce()) -> Vec<Received> {
+    let transport = MockTransport::new(&[]);
+    let handle = api.with_transport(transport.clone()).spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("request").entered();
+        emit();
+    });
+    handle.flush().await.unwrap();
+
+    transport.received()
+}
+
+fn find<'a>(requests: &'a [Received], path: &str) -> &'a Received {
+    requests
+        .iter()
+        .find(|request| request.url.ends_with(path))
+        .unwrap_or_else(|| panic!("no request to {}", path))
+}
+
+#[tokio::test]
+async fn posts_gzipped_payloads() {
+    let mut api = Api::default();
+    api.key = "license".to_owned();
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+    assert_eq!(requests.len(), 2);
+
+    for path in ["/log/v1", "/trace/v1"] {
+        let request = find(&requests, path);
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(request.headers["Api-Key"], "license");
+        // the gzip magic bytes
+        assert_eq!(request.body[..2], [0x1f, 0x8b]);
+    }
+
+    let logs = &find(&requests, "/log/v1").json[0]["logs"];
+    assert_eq!(logs[0]["attributes"]["message"], "signed in");
+    let spans = &find(&requests, "/trace/v1").json[0]["spans"];
+    assert_eq!(spans[0]["attributes"]["name"], "request");
+}
+
+#[tokio::test]
+async fn payloads_may_be_sent_uncompressed() {
+    for compress in [true, false] {
+        let mut api = Api::default();
+        api.compress = compress;
+
+        let requests = requests(api, || tracing::info!("signed in")).await;
+        assert_eq!(requests.len(), 2);
+
+        for request in &requests {
+            assert_eq!(request.headers.contains_key(CONTENT_ENCODING), compress);
+            assert_eq!(request.body == request.payload, !compress);
+            assert!(request.json.is_array());
+        }
+
+        let logs = &find(&requests, "/log/v1").json[0]["logs"];
+        assert_eq!(logs[0]["attributes"]["message"], "signed in");
+    }
+}
+
+#[tokio::test]
+async fn metrics_are_sent_to_the_metric_api() {
+    let transport = MockTransport::new(&[]);
+    let mut api = Api::default().with_transport(transport.clone());
+    api.span_metrics = true;
+    let handle = api.spawn();
+
+    tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+        let _span = tracing::info_span!("query", rows = 3).entered();
+    });
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let request = find(&received, "/metric/v1");
+    assert_eq!(request.method, Method::POST);
+
+    // a single batch, sharing the window of its metrics
+    let batches = request.json.as_array().unwrap();
+    assert_eq!(batches.len(), 1);
+    let common = &batches[0]["common"];
+    assert!(common["timestamp"].is_u64());
+    assert!(common["interval.ms"].is_u64());
+
+    let metrics = batches[0]["metrics"].as_array().unwrap();
+    let rows = metrics
+        .iter()
+        .find(|metric| metric["name"] == "rows")
+        .unwrap();
+    assert_eq!(rows["type"], "gauge");
+    assert_eq!(rows["value"], 3);
+    assert!(rows["timestamp"].is_u64());
+    assert_eq!(rows["attributes"]["span.name"], "query");
+    assert!(rows["attributes"]["service.name"].is_string());
+}
+
+#[tokio::test]
+async fn span_durations_are_summarized_by_name() {
+    let transport = MockTransport::new(&[]);
+    let mut api = Api::default().with_transport(transport.clone());
+    api.span_duration_metrics = true;
+    let handle = api.spawn();
+    let clock = ManualClock::new(UNIX_EPOCH);
+    let layer = handle.layer().with_clock(clock.clone());
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        for ms in [10, 30, 20] {
+            let _span = tracing::info_span!("query").entered();
+            clock.advance(Duration::from_millis(ms));
+        }
+    });
+    handle.flush().await.unwrap();
+
+    let metrics = transport.metrics();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["name"], "span.duration");
+    assert_eq!(metrics[0]["type"], "summary");
+    assert_eq!(metrics[0]["attributes"]["span.name"], "query");
+    assert_eq!(
+        metrics[0]["value"],
+        serde_json::json!({ "count": 3, "sum": 60.0, "min": 10.0, "max": 30.0 })
+    );
+}
+
+#[tokio::test]
+async fn counter_fields_become_count_metrics() {
+    let requests = requests(Api::default(), || {
+        tracing::info!(monotonic_counter.requests = 1, path = "/", "handled");
+        tracing::info!(counter.requests = 2_u64, "handled");
+    })
+    .await;
+
+    let metrics = &find(&requests, "/metric/v1").json[0]["metrics"];
+    assert_eq!(metrics.as_array().unwrap().len(), 1);
+    assert_eq!(metrics[0]["name"], "requests");
+    assert_eq!(metrics[0]["type"], "count");
+    assert_eq!(metrics[0]["value"], 3.0);
+
+    // not left as log attributes
+    let logs = &find(&requests, "/log/v1").json[0]["logs"];
+    for log in logs.as_array().unwrap() {
+        let attributes = log["attributes"].as_object().unwrap();
+        assert!(attributes.keys().all(|key| !key.contains("requests")));
+    }
+    assert_eq!(logs[0]["attributes"]["path"], "/");
+}
+
+#[tokio::test(start_paused = true)]
+async fn metrics_are_batched_by_window() {
+    let transport = MockTransport::new(&[]);
+    let mut api = Api::default().with_transport(transport.clone());
+    api.flush_interval = Duration::from_secs(60);
+    let handle = api.spawn();
+
+    let increment = |n: u64| {
+        tracing::subscriber::with_default(Registry::default().with(handle.layer()), || {
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!(counter.requests = n, "handled");
+        });
+    };
+
+    increment(1);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    increment(2);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    increment(4);
+    handle.flush().await.unwrap();
+
+    let received = transport.received();
+    let batches = find(&received, "/metric/v1").json.as_array().unwrap();
+    assert_eq!(batches.len(), 1);
+
+    // from the first increment, to the flush
+    let common = &batches[0]["common"];
+    assert_eq!(common["interval.ms"], 5_000);
+    let metrics = batches[0]["metrics"].as_array().unwrap();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0]["value"], 7.0);
+    assert_eq!(metrics[0]["timestamp"], common["timestamp"]);
+}
+
+#[tokio::test]
+async fn traces_use_the_trace_key() {
+    let mut api = Api::default();
+    api.key = "license".to_owned();
+    api.trace_key = Some("trace".to_owned());
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+    assert_eq!(find(&requests, "/log/v1").headers["Api-Key"], "license");
+    assert_eq!(find(&requests, "/trace/v1").headers["Api-Key"], "trace");
+}
+
+#[tokio::test]
+async fn common_attributes_are_sent_once() {
+    let mut api = Api::default();
+    api.common_attributes
+        .insert("environment".to_owned(), "staging".into());
+
+    let requests = requests(api, || {
+        tracing::info!(user = "alice", "signed in");
+        tracing::info!(user = "bob", "signed in");
+    })
+    .await;
+
+    let batch = &find(&requests, "/log/v1").json[0];
+    assert_eq!(batch["common"]["attributes"]["environment"], "staging");
+
+    let logs = batch["logs"].as_array().unwrap();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0]["attributes"]["user"], "alice");
+    assert_eq!(logs[1]["attributes"]["user"], "bob");
+    assert!(logs
+        .iter()
+        .all(|log| log["attributes"].get("environment").is_none()));
+}
+
+#[tokio::test]
+async fn common_attributes_merge_into_simple_logs() {
+    let mut api = Api::default();
+    api.log_format = LogFormat::Simple;
+    api.common_attributes
+        .insert("environment".to_owned(), "staging".into());
+
+    let requests = requests(api, || tracing::info!(user = "alice", "signed in")).await;
+
+    let logs = find(&requests, "/log/v1").json.as_array().unwrap().clone();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["environment"], "staging");
+    assert_eq!(logs[0]["user"], "alice");
+    assert!(logs[0].get("common").is_none());
+}
+
+#[tokio::test]
+async fn service_and_host_names_are_common_attributes() {
+    let mut api = Api::default();
+    api.service_name = Some("checkout".to_owned());
+    api.host_name = Some("web-1".to_owned());
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+
+    for path in ["/log/v1", "/trace/v1"] {
+        let common = &find(&requests, path).json[0]["common"]["attributes"];
+        assert_eq!(common["service.name"], "checkout");
+        assert_eq!(common["host.name"], "web-1");
+    }
+}
+
+#[tokio::test]
+async fn entity_attributes_are_common_attributes() {
+    let api = ApiBuilder::new("NRAK-KEY")
+        .with_entity_guid("MXxBUE18QVBQTElDQVRJT058MQ")
+        .with_instrumentation_provider("tracing")
+        .build()
+        .unwrap();
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+
+    for path in ["/log/v1", "/trace/v1"] {
+        let common = &find(&requests, path).json[0]["common"]["attributes"];
+        assert_eq!(common["entity.guid"], "MXxBUE18QVBQTElDQVRJT058MQ");
+        assert_eq!(common["instrumentation.provider"], "tracing");
+    }
+}
+
+#[tokio::test]
+async fn service_name_defaults_to_the_executable() {
+    let requests = requests(Api::default(), || tracing::info!("signed in")).await;
+    let exe = std::env::current_exe().unwrap();
+    let name = exe.file_stem().unwrap().to_str().unwrap();
+
+    for path in ["/log/v1", "/trace/v1"] {
+        let common = &find(&requests, path).json[0]["common"]["attributes"];
+        assert_eq!(common["service.name"], name);
+    }
+}
+
+// payloads given to `on_payload`, parsed
+fn inspected(api: &mut Api) -> Arc<Mutex<Vec<(Signal, serde_json::Value)>>> {
+    let payloads = Arc::new(Mutex::new(Vec::new()));
+    let inspected = payloads.clone();
+    api.on_payload = Some(Arc::new(move |signal, json: &[u8]| {
+        let json = serde_json::from_slice(json).unwrap();
+        inspected.lock().unwrap().push((signal, json));
+    }));
+    payloads
+}
+
+#[tokio::test]
+async fn payloads_are_inspected_before_sending() {
+    let mut api = Api::default();
+    let payloads = inspected(&mut api);
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+
+    let payloads = payloads.lock().unwrap();
+    let (_, logs) = payloads
+        .iter()
+        .find(|(signal, _)| *signal == Signal::Logs)
+        .unwrap();
+    assert_eq!(logs[0]["logs"][0]["attributes"]["message"], "signed in");
+    assert_eq!(*logs, find(&requests, "/log/v1").json);
+}
+
+#[tokio::test]
+async fn dry_runs_send_nothing() {
+    let mut api = Api::default();
+    api.dry_run = true;
+    let payloads = inspected(&mut api);
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+    assert!(requests.is_empty());
+
+    let payloads = payloads.lock().unwrap();
+    let signals: Vec<_> = payloads.iter().map(|(signal, _)| *signal).collect();
+    assert!(signals.contains(&Signal::Logs));
+    assert!(signals.contains(&Signal::Spans));
+
+    let (_, logs) = payloads
+        .iter()
+        .find(|(signal, _)| *signal == Signal::Logs)
+        .unwrap();
+    assert_eq!(logs[0]["logs"][0]["attributes"]["message"], "signed in");
+}
+
+#[tokio::test]
+async fn extra_headers_are_sent_with_every_signal() {
+    let mut api = Api::default();
+    api.extra_headers.insert("X-Route", "eu-1".parse().unwrap());
+
+    let requests = requests(api, || tracing::info!("signed in")).await;
+
+    for path in ["/log/v1", "/trace/v1"] {
+        assert_eq!(find(&requests, path).headers["X-Route"], "eu-1");
+    }
+}
+
+#[tokio::test]
+async fn disabled_signals_are_not_sent() {
+    let mut api = Api::default();
+    api.traces_enabled = false;
+
+    let logs_only = requests(api, || tracing::info!("signed in")).await;
+    assert_eq!(logs_only.len(), 1);
+    assert!(logs_only[0].url.ends_with("/log/v1"));
+
+    let mut api = Api::default();
+    api.logs_enabled = false;
+
+    let traces_only = requests(api, || tracing::info!("signed in")).await;
+    assert_eq!(traces_only.len(), 1);
+    assert!(traces_only[0].url.ends_with("/trace/v1"));
+}
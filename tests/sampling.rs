@@ -0,0 +1,28 @@
+use tracing_newrelic::__testing::MockTransport;
+use tracing_newrelic::Api;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+const SAMPLED: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+const NOT_SAMPLED: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+
+async fn exported_spans(sample_ratio: f64, traceparent: &str) -> Vec<serde_json::Value> {
+    let transport = MockTransport::new(&[]);
+    let handle = Api::default().with_transport(transport.clone()).spawn();
+    let layer = handle.layer().with_sample_ratio(sample_ratio);
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let _span = tracing::info_span!("request", traceparent).entered();
+    });
+    handle.flush().await.unwrap();
+
+    transport.spans()
+}
+
+#[tokio::test]
+async fn follows_the_parent_decision() {
+    let spans = exported_spans(0.0, SAMPLED).await;
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0]["trace.id"], "0af7651916cd43dd8448eb211c80319c");
+
+    assert!(exported_spans(1.0, NOT_SAMPLED).await.is_empty());
+}